@@ -0,0 +1,217 @@
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::bail;
+
+use crate::{
+    swarm::Swarm,
+    torrent::Torrent,
+    tracker::{Event, ResponseType, TrackerList, TrackerRequest},
+};
+
+/// How long to wait before announcing again when no tracker has answered yet.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// The `stopped` announce is a courtesy, it should not hold up shutting down.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Keeps the trackers of a torrent up to date with a download.
+///
+/// Sends `started` first, re-announces at the interval the trackers ask for with the transfer
+/// totals of the swarm, `completed` once the last piece is verified and `stopped` when the
+/// download ends.
+pub struct Announcer {
+    trackers: TrackerList,
+    info_hash: [u8; 20],
+    peer_id: String,
+    port: u16,
+    /// Sent with every announce so trackers recognize us if our address changes.
+    key: u32,
+    started: bool,
+    completed: bool,
+}
+
+impl Announcer {
+    pub fn new(torrent: &Torrent, peer_id: String, port: u16) -> Self {
+        Announcer {
+            trackers: TrackerList::new(torrent),
+            info_hash: torrent.info_hash(),
+            peer_id,
+            port,
+            key: rand::random(),
+            started: false,
+            completed: false,
+        }
+    }
+
+    /// Announces the start of the download and returns the peers the tracker knows.
+    ///
+    /// A download that is already complete does not announce `completed` later on.
+    pub async fn start(&mut self, swarm: &Swarm) -> anyhow::Result<Vec<SocketAddr>> {
+        self.completed = *swarm.done().borrow();
+        let peers = self.announce(swarm, Some(Event::Started)).await?;
+        self.started = true;
+        Ok(peers)
+    }
+
+    /// Re-announces at the tracker interval until dropped, feeding the peers of every answer
+    /// into the swarm, and announces `completed` as soon as the swarm is done.
+    pub async fn run(&mut self, swarm: &Swarm) {
+        let mut done = swarm.done();
+        loop {
+            let wait = self.trackers.interval().unwrap_or(RETRY_INTERVAL);
+            let event = tokio::select! {
+                _ = tokio::time::sleep(wait) => None,
+                _ = done.wait_for(|done| *done), if !self.completed => Some(Event::Completed),
+            };
+            if let Some(Event::Completed) = event {
+                self.completed = true;
+            }
+            match self.announce(swarm, event).await {
+                Ok(peers) => swarm.add_peers(peers),
+                Err(e) => eprintln!("announce: {e:#}"),
+            }
+        }
+    }
+
+    /// Announces `completed` if the swarm finished since the last announce, then `stopped`.
+    pub async fn stop(&mut self, swarm: &Swarm) {
+        if !self.started {
+            return;
+        }
+        if !self.completed && *swarm.done().borrow() {
+            self.completed = true;
+            if let Err(e) = self.announce(swarm, Some(Event::Completed)).await {
+                eprintln!("announce completed: {e:#}");
+            }
+        }
+        match tokio::time::timeout(STOP_TIMEOUT, self.announce(swarm, Some(Event::Stopped))).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => eprintln!("announce stopped: {e:#}"),
+            Err(_) => eprintln!("announce stopped: timed out"),
+        }
+        self.started = false;
+    }
+
+    async fn announce(
+        &mut self,
+        swarm: &Swarm,
+        event: Option<Event>,
+    ) -> anyhow::Result<Vec<SocketAddr>> {
+        let stats = swarm.stats();
+        let request = TrackerRequest {
+            uploaded: stats.uploaded,
+            downloaded: stats.downloaded,
+            event,
+            key: Some(self.key),
+            // Peers are no use to us once we stop.
+            numwant: (event == Some(Event::Stopped)).then_some(0),
+            ..TrackerRequest::new(self.info_hash, self.peer_id.clone(), self.port, stats.left)
+        };
+        match self.trackers.announce(&request).await?.resp_type {
+            ResponseType::Ok { peers, peers6, .. } => {
+                Ok(peers.0.into_iter().chain(peers6.0).collect())
+            }
+            ResponseType::Err { fail_reason } => bail!("{fail_reason}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        bitfield::Bitfield,
+        storage::MemoryStorage,
+        torrent::{Hashes, Info, Keys},
+        tracker::server::Server,
+    };
+
+    /// A torrent of two pieces announcing to a live tracker, and a swarm for it.
+    async fn setup() -> (Arc<Server>, Torrent, Swarm) {
+        let server = Arc::new(Server::new(Duration::from_secs(60), None));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        tokio::spawn(Arc::clone(&server).run(Some(listener), None));
+
+        let info = Info {
+            name: "a".to_string(),
+            plength: 16384,
+            pieces: Hashes(vec![[0; 20]; 2]),
+            private: None,
+            keys: Keys::SingleFile { length: 20000 },
+        };
+        let mut torrent = Torrent::new(info).unwrap();
+        torrent.announce = url;
+        let storage = Arc::new(MemoryStorage::new(torrent.info()));
+        let swarm = Swarm::new(torrent.clone(), [0; 20], storage);
+        (server, torrent, swarm)
+    }
+
+    /// Seeders, leechers and completed downloads the tracker counts.
+    fn counts(server: &Server, torrent: &Torrent) -> (u64, u64, u64) {
+        let stats = server.scrape(&[torrent.info_hash()]).unwrap()[&torrent.info_hash()];
+        (stats.complete, stats.incomplete, stats.downloaded)
+    }
+
+    fn complete(swarm: &Swarm) {
+        let mut have = Bitfield::new(2);
+        have.set(0);
+        have.set(1);
+        swarm.resume(&have);
+    }
+
+    #[tokio::test]
+    async fn announces_started_completed_and_stopped() {
+        let (server, torrent, swarm) = setup().await;
+        let mut announcer = Announcer::new(&torrent, "-RS0001-aaaaaaaaaaaa".to_string(), 6881);
+
+        announcer.start(&swarm).await.unwrap();
+        assert_eq!(counts(&server, &torrent), (0, 1, 0));
+
+        // `run` announces `completed` as soon as the swarm is done.
+        complete(&swarm);
+        tokio::select! {
+            _ = announcer.run(&swarm) => unreachable!(),
+            _ = async {
+                while counts(&server, &torrent) != (1, 0, 1) {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            } => {}
+        }
+
+        // `stop` does not announce `completed` again.
+        announcer.stop(&swarm).await;
+        assert_eq!(counts(&server, &torrent), (0, 0, 1));
+    }
+
+    #[tokio::test]
+    async fn stop_announces_a_completion_run_missed() {
+        let (server, torrent, swarm) = setup().await;
+        let mut announcer = Announcer::new(&torrent, "-RS0001-aaaaaaaaaaaa".to_string(), 6881);
+        announcer.start(&swarm).await.unwrap();
+        complete(&swarm);
+        announcer.stop(&swarm).await;
+        assert_eq!(counts(&server, &torrent), (0, 0, 1));
+    }
+
+    #[tokio::test]
+    async fn complete_downloads_never_announce_completed() {
+        let (server, torrent, swarm) = setup().await;
+        let mut announcer = Announcer::new(&torrent, "-RS0001-aaaaaaaaaaaa".to_string(), 6881);
+
+        // Nothing to stop before a start.
+        announcer.stop(&swarm).await;
+        assert_eq!(counts(&server, &torrent), (0, 0, 0));
+
+        complete(&swarm);
+        announcer.start(&swarm).await.unwrap();
+        assert_eq!(counts(&server, &torrent), (1, 0, 0));
+        tokio::select! {
+            _ = announcer.run(&swarm) => unreachable!(),
+            _ = tokio::time::sleep(Duration::from_millis(100)) => {}
+        }
+        announcer.stop(&swarm).await;
+        assert_eq!(counts(&server, &torrent), (0, 0, 0));
+    }
+}
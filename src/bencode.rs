@@ -0,0 +1,122 @@
+//! Helpers for working on raw bencoded bytes, for the cases where serde alone is not enough.
+
+/// Returns the length in bytes of the bencoded value at the start of `data`, or `None` if `data`
+/// does not start with a complete, well-formed value.
+///
+/// This is used to split a bencoded dictionary from whatever trails it (e.g. the raw metadata
+/// appended to a `ut_metadata` data message).
+pub fn value_len(data: &[u8]) -> Option<usize> {
+    // Walks the value with a count of the lists and dictionaries still open rather than
+    // recursing, the data can come from peers and be nested arbitrarily deep.
+    let mut open = 0usize;
+    let mut i = 0;
+    loop {
+        let rest = data.get(i..)?;
+        match *rest.first()? {
+            b'i' => {
+                let end = rest.iter().position(|&b| b == b'e')?;
+                i += end + 1;
+            }
+            b'l' | b'd' => {
+                open += 1;
+                i += 1;
+            }
+            b'e' if open > 0 => {
+                open -= 1;
+                i += 1;
+            }
+            b'0'..=b'9' => {
+                let colon = rest.iter().position(|&b| b == b':')?;
+                let len: usize = std::str::from_utf8(&rest[..colon]).ok()?.parse().ok()?;
+                let end = colon.checked_add(1)?.checked_add(len)?;
+                if end > rest.len() {
+                    return None;
+                }
+                i += end;
+            }
+            _ => return None,
+        }
+        if open == 0 {
+            return Some(i);
+        }
+    }
+}
+
+/// Returns the byte range of the value stored under `key` in the bencoded dictionary `data`.
+///
+/// Hashing a value has to use its original bytes, re-encoding a parsed copy drops every key the
+/// parser does not know about.
+pub fn dict_value_span(data: &[u8], key: &[u8]) -> Option<std::ops::Range<usize>> {
+    if *data.first()? != b'd' {
+        return None;
+    }
+    let mut i = 1;
+    while *data.get(i)? != b'e' {
+        // Keys are byte strings, anything else is malformed.
+        if !data[i].is_ascii_digit() {
+            return None;
+        }
+        let key_len = value_len(&data[i..])?;
+        let colon = data[i..].iter().position(|&b| b == b':')?;
+        let this_key = &data[i + colon + 1..i + key_len];
+        i += key_len;
+
+        let value_len = value_len(&data[i..])?;
+        if this_key == key {
+            return Some(i..i + value_len);
+        }
+        i += value_len;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn value_len_of_each_type() {
+        assert_eq!(value_len(b"i42e"), Some(4));
+        assert_eq!(value_len(b"4:spamXX"), Some(6));
+        assert_eq!(value_len(b"l4:spami1ee"), Some(11));
+        assert_eq!(value_len(b"d3:cow3:mooe trailing"), Some(12));
+        assert_eq!(value_len(b"le"), Some(2));
+    }
+
+    #[test]
+    fn value_len_rejects_malformed_input() {
+        assert_eq!(value_len(b""), None);
+        assert_eq!(value_len(b"i42"), None);
+        assert_eq!(value_len(b"5:spam"), None);
+        assert_eq!(value_len(b"l4:spam"), None);
+        assert_eq!(value_len(b"e"), None);
+        assert_eq!(value_len(b"x"), None);
+        assert_eq!(value_len(b"99999999999999999999999:a"), None);
+    }
+
+    #[test]
+    fn value_len_handles_deep_nesting() {
+        let depth = 1 << 20;
+        let mut data = vec![b'l'; depth];
+        assert_eq!(value_len(&data), None);
+        data.extend(std::iter::repeat_n(b'e', depth));
+        assert_eq!(value_len(&data), Some(2 * depth));
+    }
+
+    #[test]
+    fn dict_value_span_finds_the_raw_value() {
+        let data = b"d8:announce3:url4:infod4:name1:ae5:otheri1ee";
+        let span = dict_value_span(data, b"info").unwrap();
+        assert_eq!(&data[span], b"d4:name1:ae");
+        assert_eq!(dict_value_span(data, b"missing"), None);
+        assert_eq!(dict_value_span(b"l4:infoe", b"info"), None);
+        assert_eq!(dict_value_span(b"d4:info", b"info"), None);
+    }
+
+    #[test]
+    fn dict_value_span_rejects_keys_that_are_not_strings() {
+        for data in [&b"di1e1:ae"[..], b"dle1:ae", b"dde1:ae", b"d1:ai1ei1e1:ae"] {
+            assert_eq!(dict_value_span(data, b"x"), None);
+        }
+    }
+}
@@ -0,0 +1,119 @@
+/// The set of pieces a peer has, as sent in a `Bitfield` message: the high bit of the first byte
+/// is piece 0.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    /// An empty bitfield for a torrent with `len` pieces.
+    pub fn new(len: usize) -> Self {
+        Bitfield {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// Builds a bitfield for `len` pieces from a `Bitfield` message payload. Missing bytes count
+    /// as unset and spare bits past `len` are cleared.
+    pub fn from_bytes(mut bytes: Vec<u8>, len: usize) -> Self {
+        bytes.resize(len.div_ceil(8), 0);
+        if !len.is_multiple_of(8) {
+            if let Some(last) = bytes.last_mut() {
+                *last &= !(0xff >> (len % 8));
+            }
+        }
+        Bitfield { bytes, len }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn has(&self, piece_i: usize) -> bool {
+        piece_i < self.len && self.bytes[piece_i / 8] & (0x80 >> (piece_i % 8)) != 0
+    }
+
+    pub fn set(&mut self, piece_i: usize) {
+        if piece_i < self.len {
+            self.bytes[piece_i / 8] |= 0x80 >> (piece_i % 8);
+        }
+    }
+
+    pub fn unset(&mut self, piece_i: usize) {
+        if piece_i < self.len {
+            self.bytes[piece_i / 8] &= !(0x80 >> (piece_i % 8));
+        }
+    }
+
+    /// Number of pieces that are set.
+    pub fn count(&self) -> usize {
+        self.bytes.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.count() == self.len
+    }
+
+    /// Indices of the pieces that are set.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|&i| self.has(i))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_bytes_fits_the_payload_to_len() {
+        let short = Bitfield::from_bytes(vec![0xff], 12);
+        assert_eq!(short.as_bytes(), [0xff, 0]);
+        assert_eq!(short.count(), 8);
+
+        let spare = Bitfield::from_bytes(vec![0xff, 0xff, 0xff], 12);
+        assert_eq!(spare.as_bytes(), [0xff, 0xf0]);
+        assert!(spare.is_full());
+        assert!(!spare.has(12));
+
+        let aligned = Bitfield::from_bytes(vec![0xff], 8);
+        assert!(aligned.is_full());
+    }
+
+    #[test]
+    fn bits_past_len_are_ignored() {
+        let mut bitfield = Bitfield::new(9);
+        bitfield.set(0);
+        bitfield.set(8);
+        bitfield.set(9);
+        assert_eq!(bitfield.as_bytes(), [0x80, 0x80]);
+        assert_eq!(bitfield.iter().collect::<Vec<_>>(), [0, 8]);
+
+        bitfield.unset(8);
+        bitfield.unset(100);
+        assert_eq!(bitfield.iter().collect::<Vec<_>>(), [0]);
+        assert!(!bitfield.has(100));
+    }
+
+    #[test]
+    fn empty_bitfield() {
+        let bitfield = Bitfield::new(0);
+        assert!(bitfield.is_empty());
+        assert!(bitfield.is_full());
+        assert!(bitfield.as_bytes().is_empty());
+        assert_eq!(Bitfield::from_bytes(vec![0xff], 0), bitfield);
+    }
+}
@@ -0,0 +1,366 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use sha1::{Digest, Sha1};
+
+use crate::{
+    peer::BLOCK_MAX,
+    storage::{par_pieces, read_range},
+    torrent::{File, FileSpan, Hashes, Info, Keys, Torrent},
+};
+
+/// Aim for about this many pieces when the piece length is picked automatically.
+const TARGET_PIECES: u64 = 1500;
+const MAX_PIECE_LENGTH: u64 = 1 << 24;
+
+/// Authors a torrent from a file or a directory.
+#[derive(Debug, Clone)]
+pub struct TorrentBuilder {
+    path: PathBuf,
+    name: Option<String>,
+    piece_length: Option<u64>,
+    trackers: Vec<Vec<String>>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<i64>,
+    private: bool,
+    web_seeds: Vec<String>,
+}
+
+impl TorrentBuilder {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        TorrentBuilder {
+            path: path.into(),
+            name: None,
+            piece_length: None,
+            trackers: Vec::new(),
+            comment: None,
+            created_by: Some(concat!("bittorrent-rs/", env!("CARGO_PKG_VERSION")).to_string()),
+            creation_date: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|d| d.as_secs() as i64),
+            private: false,
+            web_seeds: Vec::new(),
+        }
+    }
+
+    /// Overrides the name of the torrent, the file or directory name by default.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Sets the piece length, a power of two of at least 16 KiB. Picked from the total size by
+    /// default.
+    pub fn piece_length(mut self, piece_length: u64) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    /// Adds a tracker in a tier of its own.
+    pub fn announce(self, url: impl Into<String>) -> Self {
+        self.tier(vec![url.into()])
+    }
+
+    /// Adds a tier of trackers (BEP 12). The first tracker of the first tier also becomes the
+    /// `announce` URL.
+    pub fn tier(mut self, urls: Vec<String>) -> Self {
+        if !urls.is_empty() {
+            self.trackers.push(urls);
+        }
+        self
+    }
+
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    pub fn created_by(mut self, created_by: Option<String>) -> Self {
+        self.created_by = created_by;
+        self
+    }
+
+    /// Seconds since the epoch, now by default.
+    pub fn creation_date(mut self, creation_date: Option<i64>) -> Self {
+        self.creation_date = creation_date;
+        self
+    }
+
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// Adds a web seed URL (BEP 19).
+    pub fn web_seed(mut self, url: impl Into<String>) -> Self {
+        self.web_seeds.push(url.into());
+        self
+    }
+
+    /// Walks the path and hashes its pieces, on one thread per core.
+    pub fn build(self) -> anyhow::Result<Torrent> {
+        let metadata = std::fs::metadata(&self.path)
+            .with_context(|| format!("stat {}", self.path.display()))?;
+        let name = match self.name {
+            Some(name) => name,
+            None => self
+                .path
+                .canonicalize()?
+                .file_name()
+                .and_then(|n| n.to_str())
+                .context("path has no UTF-8 file name, set a name explicitly")?
+                .to_string(),
+        };
+
+        let (keys, layout) = if metadata.is_dir() {
+            let mut files = Vec::new();
+            walk(&self.path, &mut Vec::new(), &mut files)?;
+            if files.is_empty() {
+                bail!("{} contains no files", self.path.display());
+            }
+            files.sort_by(|a, b| a.path.cmp(&b.path));
+
+            let mut offset = 0;
+            let layout = files
+                .iter()
+                .map(|file| {
+                    let span = FileSpan {
+                        path: file.path.iter().fold(self.path.clone(), |p, c| p.join(c)),
+                        offset,
+                        length: file.length,
+                    };
+                    offset += file.length;
+                    span
+                })
+                .collect();
+            (Keys::MultiFile { files }, layout)
+        } else {
+            let length = metadata.len();
+            let span = FileSpan {
+                path: self.path.clone(),
+                offset: 0,
+                length,
+            };
+            (Keys::SingleFile { length }, vec![span])
+        };
+
+        let length: u64 = layout.iter().map(|span| span.length).sum();
+        let plength = match self.piece_length {
+            Some(plength) => {
+                if plength < BLOCK_MAX || !plength.is_power_of_two() {
+                    bail!("piece length must be a power of two of at least {BLOCK_MAX}");
+                }
+                plength
+            }
+            None => (length / TARGET_PIECES)
+                .next_power_of_two()
+                .clamp(BLOCK_MAX, MAX_PIECE_LENGTH),
+        };
+
+        let npieces = length.div_ceil(plength) as usize;
+        let hashes = par_pieces(npieces, layout.len(), |files, piece_i| {
+            let offset = piece_i as u64 * plength;
+            let mut data = vec![0; (length - offset).min(plength) as usize];
+            read_range(&layout, files, offset, &mut data)?;
+            let mut hasher = Sha1::new();
+            hasher.update(&data);
+            Ok::<[u8; 20], std::io::Error>(hasher.finalize().into())
+        })
+        .into_iter()
+        .collect::<Result<_, _>>()
+        .context("read files to hash")?;
+
+        let mut trackers = self.trackers;
+        let announce = trackers
+            .first()
+            .and_then(|tier| tier.first())
+            .cloned()
+            .unwrap_or_default();
+        let announce_list = if trackers.iter().map(Vec::len).sum::<usize>() > 1 {
+            Some(std::mem::take(&mut trackers))
+        } else {
+            None
+        };
+
+        let mut torrent = Torrent::new(Info {
+            name,
+            plength,
+            pieces: Hashes(hashes),
+            private: self.private.then_some(1),
+            keys,
+        })?;
+        torrent.announce = announce;
+        torrent.announce_list = announce_list;
+        torrent.comment = self.comment;
+        torrent.created_by = self.created_by;
+        torrent.creation_date = self.creation_date;
+        torrent.url_list = (!self.web_seeds.is_empty()).then_some(self.web_seeds);
+        Ok(torrent)
+    }
+}
+
+/// Collects the files under `dir` with their paths relative to the root of the walk.
+fn walk(dir: &Path, prefix: &mut Vec<String>, files: &mut Vec<File>) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir).with_context(|| format!("list {}", dir.display()))? {
+        let entry = entry?;
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|n| anyhow::anyhow!("file name {n:?} is not UTF-8"))?;
+        let path = entry.path();
+        // Symlinks to files are followed, like reading the file later does. Symlinked
+        // directories are skipped, they could point back up the tree.
+        let mut metadata = entry
+            .metadata()
+            .with_context(|| format!("stat {}", path.display()))?;
+        if metadata.is_symlink() {
+            metadata =
+                std::fs::metadata(&path).with_context(|| format!("stat {}", path.display()))?;
+            if metadata.is_dir() {
+                continue;
+            }
+        }
+        prefix.push(name);
+        if metadata.is_dir() {
+            walk(&path, prefix, files)?;
+        } else if metadata.is_file() {
+            files.push(File {
+                length: metadata.len(),
+                path: prefix.clone(),
+            });
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn walk_skips_symlinked_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub/a"), b"abc").unwrap();
+        std::os::unix::fs::symlink(dir.path(), dir.path().join("sub/loop")).unwrap();
+        std::os::unix::fs::symlink(dir.path().join("sub/a"), dir.path().join("b")).unwrap();
+
+        let mut files = Vec::new();
+        walk(dir.path(), &mut Vec::new(), &mut files).unwrap();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        let files: Vec<_> = files.into_iter().map(|f| (f.path, f.length)).collect();
+        assert_eq!(
+            files,
+            [
+                (vec!["b".to_string()], 3),
+                (vec!["sub".to_string(), "a".to_string()], 3)
+            ]
+        );
+    }
+
+    fn data(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+            .collect()
+    }
+
+    fn hashes(data: &[u8], plength: usize) -> Vec<[u8; 20]> {
+        data.chunks(plength)
+            .map(|c| Sha1::digest(c).into())
+            .collect()
+    }
+
+    #[test]
+    fn build_hashes_a_directory_across_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        let (a, b) = (data(30000, 1), data(20000, 2));
+        std::fs::write(root.join("sub/a"), &a).unwrap();
+        std::fs::write(root.join("b"), &b).unwrap();
+
+        let torrent = TorrentBuilder::new(&root)
+            .piece_length(16384)
+            .tier(vec!["http://t1".to_string(), "http://t2".to_string()])
+            .announce("http://t3")
+            .comment("hi")
+            .private(true)
+            .build()
+            .unwrap();
+        let info = torrent.info();
+        assert_eq!(info.name, "root");
+        assert_eq!(info.plength, 16384);
+        assert_eq!(info.private, Some(1));
+        // Files are in path order, and pieces run on from one file into the next.
+        let Keys::MultiFile { files } = &info.keys else {
+            panic!("expected a multi-file torrent");
+        };
+        let files: Vec<_> = files.iter().map(|f| (f.path.join("/"), f.length)).collect();
+        assert_eq!(
+            files,
+            [("b".to_string(), 20000), ("sub/a".to_string(), 30000)]
+        );
+        assert_eq!(info.pieces.0, hashes(&[b, a].concat(), 16384));
+
+        assert_eq!(torrent.announce, "http://t1");
+        assert_eq!(
+            torrent.announce_list,
+            Some(vec![
+                vec!["http://t1".to_string(), "http://t2".to_string()],
+                vec!["http://t3".to_string()],
+            ])
+        );
+        assert_eq!(torrent.comment.as_deref(), Some("hi"));
+    }
+
+    #[test]
+    fn build_a_single_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.bin");
+        let content = data(40000, 3);
+        std::fs::write(&path, &content).unwrap();
+
+        let torrent = TorrentBuilder::new(&path)
+            .name("renamed")
+            .announce("http://t")
+            .build()
+            .unwrap();
+        let info = torrent.info();
+        assert_eq!(info.name, "renamed");
+        assert!(matches!(info.keys, Keys::SingleFile { length: 40000 }));
+        // Small torrents get the smallest piece length.
+        assert_eq!(info.plength, BLOCK_MAX);
+        assert_eq!(info.pieces.0, hashes(&content, BLOCK_MAX as usize));
+        assert_eq!(torrent.announce, "http://t");
+        assert_eq!(torrent.announce_list, None);
+    }
+
+    #[test]
+    fn build_rejects_bad_piece_lengths_and_empty_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("empty")).unwrap();
+        let err = TorrentBuilder::new(dir.path().join("empty"))
+            .build()
+            .unwrap_err();
+        assert!(err.to_string().ends_with("contains no files"), "{err}");
+
+        std::fs::write(dir.path().join("f"), b"abc").unwrap();
+        for plength in [0, 1024, 3 * 16384] {
+            let err = TorrentBuilder::new(dir.path().join("f"))
+                .piece_length(plength)
+                .build()
+                .unwrap_err();
+            assert!(err.to_string().starts_with("piece length"), "{err}");
+        }
+        assert!(TorrentBuilder::new(dir.path().join("missing"))
+            .build()
+            .is_err());
+    }
+}
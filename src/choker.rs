@@ -0,0 +1,121 @@
+use std::{cmp::Reverse, collections::HashSet, time::Duration};
+
+use rand::seq::IteratorRandom;
+
+/// How often the choker reconsiders which peers to unchoke.
+pub const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+/// The optimistic unchoke moves on every this many rounds, every 30 seconds.
+const OPTIMISTIC_ROUNDS: u64 = 3;
+
+/// A peer as the choker sees it.
+#[derive(Debug, Clone, Copy)]
+pub struct Candidate {
+    pub id: u64,
+    pub interested: bool,
+    /// Bytes per second the peer sent us while we download, or we sent it while we seed.
+    pub rate: u64,
+}
+
+/// The tit-for-tat choker of BEP 3.
+///
+/// Every round the interested peers with the best rates get the regular unchoke slots, and one
+/// more interested peer gets an optimistic unchoke, so new peers get a chance to prove
+/// themselves. The optimistic unchoke is only rotated every few rounds.
+#[derive(Debug)]
+pub struct Choker {
+    slots: usize,
+    round: u64,
+    optimistic: Option<u64>,
+}
+
+impl Choker {
+    pub fn new(slots: usize) -> Self {
+        Choker {
+            slots,
+            round: 0,
+            optimistic: None,
+        }
+    }
+
+    /// Runs a round, every `CHOKE_INTERVAL`. Returns the peers to unchoke, the others get
+    /// choked.
+    pub fn round(&mut self, candidates: &[Candidate]) -> HashSet<u64> {
+        let mut interested: Vec<&Candidate> = candidates.iter().filter(|c| c.interested).collect();
+        interested.sort_by_key(|c| Reverse(c.rate));
+        let mut unchoked: HashSet<u64> = interested.iter().take(self.slots).map(|c| c.id).collect();
+
+        let still_there = self
+            .optimistic
+            .is_some_and(|id| interested.iter().any(|c| c.id == id));
+        if self.round.is_multiple_of(OPTIMISTIC_ROUNDS) || !still_there {
+            self.optimistic = interested
+                .iter()
+                .map(|c| c.id)
+                .filter(|id| !unchoked.contains(id))
+                .choose(&mut rand::thread_rng());
+        }
+        unchoked.extend(self.optimistic);
+        self.round += 1;
+        unchoked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidates(rates: &[(bool, u64)]) -> Vec<Candidate> {
+        rates
+            .iter()
+            .enumerate()
+            .map(|(id, &(interested, rate))| Candidate {
+                id: id as u64,
+                interested,
+                rate,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unchokes_the_fastest_interested_peers_and_one_more() {
+        let mut choker = Choker::new(2);
+        let peers = candidates(&[(true, 10), (false, 1000), (true, 30), (true, 20), (true, 0)]);
+        let unchoked = choker.round(&peers);
+        assert_eq!(unchoked.len(), 3);
+        assert!(unchoked.contains(&2) && unchoked.contains(&3));
+        assert!(!unchoked.contains(&1));
+        let optimistic = choker.optimistic.unwrap();
+        assert!([0, 4].contains(&optimistic));
+        assert!(unchoked.contains(&optimistic));
+    }
+
+    #[test]
+    fn optimistic_unchoke_rotates_every_few_rounds() {
+        let mut choker = Choker::new(1);
+        let peers = candidates(&[(true, 100), (true, 0), (true, 0), (true, 0)]);
+
+        // The optimistic unchoke holds for a few rounds.
+        choker.round(&peers);
+        let first = choker.optimistic.unwrap();
+        for _ in 1..OPTIMISTIC_ROUNDS {
+            assert!(choker.round(&peers).contains(&first));
+            assert_eq!(choker.optimistic, Some(first));
+        }
+
+        // A peer that lost interest is replaced right away.
+        let mut peers = peers;
+        peers[first as usize].interested = false;
+        let unchoked = choker.round(&peers);
+        assert!(!unchoked.contains(&first));
+        assert_eq!(unchoked.len(), 2);
+    }
+
+    #[test]
+    fn spare_slots_leave_no_optimistic_unchoke() {
+        let mut choker = Choker::new(4);
+        let unchoked = choker.round(&candidates(&[(true, 0), (true, 5), (false, 0)]));
+        assert_eq!(unchoked, HashSet::from([0, 1]));
+        assert_eq!(choker.optimistic, None);
+        assert!(choker.round(&[]).is_empty());
+    }
+}
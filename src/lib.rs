@@ -1,3 +1,14 @@
+pub mod announcer;
+pub mod bencode;
+pub mod bitfield;
+pub mod builder;
+pub mod choker;
+pub mod magnet;
 pub mod peer;
+pub mod picker;
+pub mod resume;
+pub mod storage;
+pub mod swarm;
 pub mod torrent;
 pub mod tracker;
+pub mod verify;
@@ -0,0 +1,396 @@
+use std::{collections::BTreeMap, net::SocketAddr, str::FromStr, time::Duration};
+
+use anyhow::{bail, Context};
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+use tokio_util::codec::Framed;
+
+use crate::{
+    bencode,
+    peer::{Handshake, MessageCodec, PeerMessage},
+    torrent::Torrent,
+};
+
+/// Extended message id we ask peers to use for the `ut_metadata` messages they send us.
+const UT_METADATA_ID: u8 = 1;
+/// Extended message id reserved for the extension handshake itself.
+const EXTENSION_HANDSHAKE_ID: u8 = 0;
+/// The metadata is exchanged in pieces of 16 KiB, only the last one can be smaller.
+const METADATA_PIECE: usize = 1 << 14;
+/// Refuse metadata larger than this, a peer could otherwise make us allocate arbitrary amounts.
+const METADATA_MAX: usize = 1 << 24;
+/// Time a peer gets to accept the connection and answer the handshake.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time a peer gets to send each message we wait for.
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(30);
+
+const MSG_REQUEST: u8 = 0;
+const MSG_DATA: u8 = 1;
+const MSG_REJECT: u8 = 2;
+
+/// A parsed `magnet:?xt=urn:btih:...` link.
+#[derive(Debug, Clone)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+}
+
+impl FromStr for Magnet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let query = s
+            .strip_prefix("magnet:?")
+            .context("magnet link should start with `magnet:?`")?;
+        let params: Vec<(String, String)> =
+            serde_urlencoded::from_str(query).context("parse magnet link parameters")?;
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        for (key, value) in params {
+            match key.as_str() {
+                "xt" => {
+                    if let Some(btih) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_btih(btih)?);
+                    }
+                }
+                "dn" => name = Some(value),
+                "tr" => trackers.push(value),
+                _ => {}
+            }
+        }
+
+        Ok(Magnet {
+            info_hash: info_hash.context("magnet link has no `urn:btih` exact topic")?,
+            name,
+            trackers,
+        })
+    }
+}
+
+/// The btih is either 40 hex characters or 32 base32 characters.
+fn parse_btih(btih: &str) -> anyhow::Result<[u8; 20]> {
+    match btih.len() {
+        40 => {
+            let mut info_hash = [0; 20];
+            hex::decode_to_slice(btih, &mut info_hash).context("decode hex info hash")?;
+            Ok(info_hash)
+        }
+        32 => base32_decode(btih).context("decode base32 info hash"),
+        n => bail!("info hash has an invalid length of {n}"),
+    }
+}
+
+fn base32_decode(s: &str) -> Option<[u8; 20]> {
+    let mut out = Vec::with_capacity(20);
+    let mut buf = 0u32;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let v = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buf = (buf << 5) | v as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buf >> bits) as u8);
+            buf &= (1 << bits) - 1;
+        }
+    }
+    out.try_into().ok()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ExtensionHandshake {
+    m: BTreeMap<String, u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata_size: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MetadataMessage {
+    msg_type: u8,
+    piece: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+fn extended<T: Serialize>(id: u8, msg: &T) -> anyhow::Result<PeerMessage> {
+    Ok(PeerMessage::Extended {
+        id,
+        payload: serde_bencode::to_bytes(msg)
+            .context("encode extended message")?
+            .into(),
+    })
+}
+
+/// Waits for the next message of the peer, for at most `MESSAGE_TIMEOUT`.
+async fn recv(peer: &mut Framed<TcpStream, MessageCodec>) -> anyhow::Result<PeerMessage> {
+    timeout(MESSAGE_TIMEOUT, peer.next())
+        .await
+        .context("peer went silent")?
+        .context("peer closed the connection")?
+        .context("peer msg was invalid")
+}
+
+impl Magnet {
+    /// Fetches the bencoded info dictionary from `peer` with the extension protocol (BEP 10) and
+    /// the `ut_metadata` extension (BEP 9). The metadata is only returned once its SHA-1 matches
+    /// the info hash of the link.
+    pub async fn fetch_metadata(
+        &self,
+        peer: SocketAddr,
+        peer_id: [u8; 20],
+    ) -> anyhow::Result<Vec<u8>> {
+        let (stream, handshake) = timeout(CONNECT_TIMEOUT, async {
+            let mut stream = TcpStream::connect(peer).await.context("connect to peer")?;
+
+            let handshake = Handshake::new(self.info_hash, peer_id).with_extensions();
+            let mut handshake_b = handshake.to_bytes();
+            stream.write_all(&handshake_b).await?;
+            stream.read_exact(&mut handshake_b).await?;
+            anyhow::Ok((stream, Handshake::from_bytes(&handshake_b)))
+        })
+        .await
+        .context("connect timed out")??;
+        if &handshake.msg != b"BitTorrent protocol" {
+            bail!("peer sent an invalid handshake");
+        }
+        if handshake.info_hash != self.info_hash {
+            bail!("peer answered with another info hash");
+        }
+        if !handshake.supports_extensions() {
+            bail!("peer does not support the extension protocol");
+        }
+
+        let mut peer = Framed::new(stream, MessageCodec::default());
+        let our_handshake = ExtensionHandshake {
+            m: BTreeMap::from([("ut_metadata".to_string(), UT_METADATA_ID)]),
+            metadata_size: None,
+        };
+        peer.send(extended(EXTENSION_HANDSHAKE_ID, &our_handshake)?)
+            .await
+            .context("send extension handshake")?;
+
+        // The peer can send its bitfield (and anything else) before its extension handshake.
+        let (ut_metadata, size) = loop {
+            let msg = recv(&mut peer).await?;
+            let PeerMessage::Extended {
+                id: EXTENSION_HANDSHAKE_ID,
+                payload,
+            } = msg
+            else {
+                continue;
+            };
+            let theirs: ExtensionHandshake =
+                serde_bencode::from_bytes(&payload).context("parse extension handshake")?;
+            let id = theirs
+                .m
+                .get("ut_metadata")
+                .copied()
+                .filter(|&id| id != 0)
+                .context("peer does not support ut_metadata")?;
+            let size = theirs
+                .metadata_size
+                .context("peer did not announce metadata_size")?;
+            break (id, size);
+        };
+        if size == 0 || size > METADATA_MAX {
+            bail!("peer announced an invalid metadata size of {size}");
+        }
+
+        let mut metadata = Vec::with_capacity(size);
+        for piece in 0..size.div_ceil(METADATA_PIECE) {
+            let piece_len = METADATA_PIECE.min(size - piece * METADATA_PIECE);
+            let request = MetadataMessage {
+                msg_type: MSG_REQUEST,
+                piece,
+                total_size: None,
+            };
+            peer.send(extended(ut_metadata, &request)?)
+                .await
+                .with_context(|| format!("request metadata piece {piece}"))?;
+
+            loop {
+                let msg = recv(&mut peer).await?;
+                let PeerMessage::Extended {
+                    id: UT_METADATA_ID,
+                    payload,
+                } = msg
+                else {
+                    continue;
+                };
+                // A data message is a bencoded dictionary directly followed by the metadata piece.
+                let dict_len =
+                    bencode::value_len(&payload).context("malformed ut_metadata message")?;
+                let header: MetadataMessage = serde_bencode::from_bytes(&payload[..dict_len])
+                    .context("parse ut_metadata message")?;
+                match header.msg_type {
+                    MSG_DATA if header.piece == piece => {
+                        let data = &payload[dict_len..];
+                        if data.len() != piece_len {
+                            bail!(
+                                "metadata piece {piece} has {} bytes, expected {piece_len}",
+                                data.len()
+                            );
+                        }
+                        metadata.extend_from_slice(data);
+                        break;
+                    }
+                    MSG_REJECT => bail!("peer rejected metadata piece {piece}"),
+                    _ => continue,
+                }
+            }
+        }
+        let mut hasher = Sha1::new();
+        hasher.update(&metadata);
+        let hash: [u8; 20] = hasher.finalize().into();
+        if hash != self.info_hash {
+            bail!("metadata does not match the info hash of the magnet link");
+        }
+        Ok(metadata)
+    }
+
+    /// Builds a torrent from the link and the metadata fetched with [`Magnet::fetch_metadata`].
+    pub fn to_torrent(&self, metadata: Vec<u8>) -> anyhow::Result<Torrent> {
+        let announce = self.trackers.first().cloned().unwrap_or_default();
+        let mut torrent =
+            Torrent::from_info_bytes(announce, metadata).context("parse info dictionary")?;
+        if self.trackers.len() > 1 {
+            torrent.announce_list = Some(self.trackers.iter().map(|tr| vec![tr.clone()]).collect());
+        }
+        Ok(torrent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hex_and_base32_info_hashes() {
+        let hex: Magnet = "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f&dn=sample.torrent&tr=http%3A%2F%2Ft.example%2Fannounce&tr=udp%3A%2F%2Fu.example%3A80"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            hex.info_hash,
+            <[u8; 20]>::try_from(hex::decode("d69f91e6b2ae4c542468d1073a71d4ea13879a7f").unwrap())
+                .unwrap()
+        );
+        assert_eq!(hex.name.as_deref(), Some("sample.torrent"));
+        assert_eq!(
+            hex.trackers,
+            ["http://t.example/announce", "udp://u.example:80"]
+        );
+
+        let base32: Magnet = "magnet:?xt=urn:btih:22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7"
+            .parse()
+            .unwrap();
+        assert_eq!(base32.info_hash, hex.info_hash);
+    }
+
+    /// Serves `pieces` as the metadata pieces of a torrent whose metadata is `size` bytes and
+    /// hashes to `info_hash`, to one connection.
+    async fn metadata_peer(info_hash: [u8; 20], size: usize, pieces: Vec<Vec<u8>>) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0; Handshake::LEN];
+            stream.read_exact(&mut handshake).await.unwrap();
+            let ours = Handshake::new(info_hash, [2; 20]).with_extensions();
+            stream.write_all(&ours.to_bytes()).await.unwrap();
+
+            let mut peer = Framed::new(stream, MessageCodec::default());
+            let handshake = ExtensionHandshake {
+                m: BTreeMap::from([("ut_metadata".to_string(), 3)]),
+                metadata_size: Some(size),
+            };
+            peer.send(extended(EXTENSION_HANDSHAKE_ID, &handshake).unwrap())
+                .await
+                .unwrap();
+            for (piece, data) in pieces.into_iter().enumerate() {
+                // Wait for the request of each piece.
+                while !matches!(
+                    peer.next().await,
+                    Some(Ok(PeerMessage::Extended { id: 3, .. }))
+                ) {}
+                let header = MetadataMessage {
+                    msg_type: MSG_DATA,
+                    piece,
+                    total_size: Some(size),
+                };
+                let mut payload = serde_bencode::to_bytes(&header).unwrap();
+                payload.extend(data);
+                peer.send(PeerMessage::Extended {
+                    id: UT_METADATA_ID,
+                    payload: payload.into(),
+                })
+                .await
+                .unwrap();
+            }
+        });
+        addr
+    }
+
+    fn magnet(metadata: &[u8]) -> Magnet {
+        Magnet {
+            info_hash: Sha1::digest(metadata).into(),
+            name: None,
+            trackers: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn fetches_metadata_in_pieces() {
+        let metadata = vec![7; METADATA_PIECE + 10];
+        let magnet = magnet(&metadata);
+        let pieces = metadata
+            .chunks(METADATA_PIECE)
+            .map(<[u8]>::to_vec)
+            .collect();
+        let peer = metadata_peer(magnet.info_hash, metadata.len(), pieces).await;
+        assert_eq!(
+            magnet.fetch_metadata(peer, [1; 20]).await.unwrap(),
+            metadata
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_metadata_pieces_of_the_wrong_size() {
+        let metadata = vec![7; METADATA_PIECE + 10];
+        let magnet = magnet(&metadata);
+        for pieces in [
+            vec![vec![7; (1 << 20) - 100]],
+            vec![vec![7; METADATA_PIECE], vec![7; 11]],
+            vec![vec![7; METADATA_PIECE - 1]],
+        ] {
+            let peer = metadata_peer(magnet.info_hash, metadata.len(), pieces).await;
+            let err = magnet.fetch_metadata(peer, [1; 20]).await.unwrap_err();
+            assert!(err.to_string().starts_with("metadata piece"), "{err:#}");
+        }
+    }
+
+    #[test]
+    fn rejects_links_without_a_valid_info_hash() {
+        for link in [
+            "http://example.com",
+            "magnet:?dn=x",
+            "magnet:?xt=urn:btih:abc",
+            "magnet:?xt=urn:btih:zz9f91e6b2ae4c542468d1073a71d4ea13879a7f",
+            "magnet:?xt=urn:btih:22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT1",
+        ] {
+            assert!(link.parse::<Magnet>().is_err(), "{link}");
+        }
+    }
+}
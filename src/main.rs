@@ -1,26 +1,40 @@
 #![allow(dead_code)]
 use anyhow::{bail, Context};
-use clap::{Parser, Subcommand};
-use core::panic;
-use futures::{SinkExt, StreamExt};
+use clap::{ArgGroup, Parser, Subcommand};
 use nanoid::nanoid;
-use rand::seq::SliceRandom;
-use rand::thread_rng;
-use sha1::{Digest, Sha1};
-use std::{env, net::SocketAddrV4, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-    sync::{mpsc::Receiver, mpsc::Sender},
+    net::{TcpListener, TcpStream, UdpSocket},
 };
 
 use bittorrent_rs::{
-    peer::{Handshake, Message, MessageCodec, MessageTag, Piece, Request},
-    torrent::{self, Torrent},
-    tracker::{ResponseType, TrackerRequest, TrackerResponse},
+    announcer::Announcer,
+    bitfield::Bitfield,
+    builder::TorrentBuilder,
+    magnet::Magnet,
+    peer::Handshake,
+    resume::FastResume,
+    storage::FileStorage,
+    swarm::{self, Swarm},
+    torrent::{self, FileSpan, Torrent},
+    tracker::{self, server::Server, ResponseType, TrackerList, TrackerRequest},
+    verify::{self, Status},
 };
 
-const BLOCK_MAX: u64 = 1 << 14;
+/// Port we accept peer connections on unless told otherwise.
+const PEER_PORT: u16 = 6881;
+/// How often the fast-resume file is saved while downloading.
+const RESUME_INTERVAL: Duration = Duration::from_secs(30);
+/// What we announce as `left` while fetching the metadata of a magnet link, whose size is not
+/// known yet. Anything but 0 keeps trackers from taking us for a seeder and leaving out seeders.
+const MAGNET_LEFT: u64 = 1;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -55,16 +69,76 @@ enum Command {
         #[arg(short)]
         output: PathBuf,
         torrent: PathBuf,
+        /// Fast-resume file, saved while downloading and used to skip the recheck on restart.
+        #[arg(long)]
+        resume: Option<PathBuf>,
+        /// Keep uploading to other peers once the download is complete, until interrupted.
+        #[arg(long)]
+        seed: bool,
+        /// Port we accept peer connections on.
+        #[arg(short, long, default_value_t = PEER_PORT)]
+        port: u16,
+    },
+    /// Recheck local data against a torrent, exits with an error if anything is off.
+    Verify {
+        torrent: PathBuf,
+        path: PathBuf,
+    },
+    /// Create a .torrent file from a file or directory.
+    Create {
+        #[arg(short)]
+        output: PathBuf,
+        path: PathBuf,
+        /// Tracker URL, repeat for more tiers; comma-separate the trackers of one tier.
+        #[arg(short, long)]
+        announce: Vec<String>,
+        #[arg(long)]
+        comment: Option<String>,
+        #[arg(long)]
+        private: bool,
+        #[arg(long = "web-seed")]
+        web_seeds: Vec<String>,
+        /// Piece length in bytes, picked from the total size when omitted.
+        #[arg(long)]
+        piece_length: Option<u64>,
+    },
+    /// Ask the trackers of torrents for their seeder and leecher counts.
+    Scrape {
+        #[arg(required = true)]
+        torrents: Vec<PathBuf>,
+    },
+    /// Run a tracker.
+    Tracker {
+        #[command(subcommand)]
+        command: TrackerCommand,
+    },
+    /// Fetch the metadata of a magnet link, and download it when an output is given.
+    Magnet {
+        #[arg(short)]
+        output: Option<PathBuf>,
+        link: String,
     },
 }
 
-fn urlencoded(info_hash: &[u8; 20]) -> String {
-    let mut encoded = String::with_capacity(3 * info_hash.len());
-    for &byte in info_hash {
-        encoded.push('%');
-        encoded.push_str(&hex::encode([byte]));
-    }
-    encoded
+#[derive(Subcommand, Debug)]
+enum TrackerCommand {
+    /// Serve announces and scrapes over HTTP, UDP or both.
+    #[command(group(ArgGroup::new("listen").required(true).multiple(true).args(["http", "udp"])))]
+    Serve {
+        /// Address of the HTTP tracker, e.g. 0.0.0.0:6969.
+        #[arg(long)]
+        http: Option<SocketAddr>,
+        /// Address of the UDP tracker (BEP 15).
+        #[arg(long)]
+        udp: Option<SocketAddr>,
+        /// Seconds clients wait between announces.
+        #[arg(long, default_value_t = 1800, value_parser = clap::value_parser!(u64).range(1..))]
+        interval: u64,
+        /// Hex info hash of a torrent to track, repeat for more. Any torrent is tracked when
+        /// none is given.
+        #[arg(long)]
+        allow: Vec<String>,
+    },
 }
 
 #[tokio::main]
@@ -78,86 +152,54 @@ async fn main() -> anyhow::Result<()> {
         }
         Command::Info { torrent } => {
             let torrent_f = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent = serde_bencode::from_bytes(&torrent_f).context("parse torrent file")?;
+            let t = Torrent::from_bytes(&torrent_f).context("parse torrent file")?;
 
             println!("Tracker URL: {}", t.announce);
-            if let torrent::Keys::SingleFile { length } = t.info.keys {
-                println!("Length: {length}");
-            } else {
-                todo!();
+            println!("Length: {}", t.info().length());
+            if let torrent::Keys::MultiFile { files } = &t.info().keys {
+                println!("Files:");
+                for file in files {
+                    println!("\t{} ({} bytes)", file.path.join("/"), file.length);
+                }
             }
-            let info_bencoded = serde_bencode::to_bytes(&t.info).context("re-encode info")?;
             println!("{:?}", t);
-            let mut hasher = Sha1::new();
-            hasher.update(&info_bencoded);
-            let info_hash = hasher.finalize();
-            println!("Info Hash: {}", hex::encode(info_hash));
-            println!("Piece Length: {}", t.info.plength);
+            println!("Info Hash: {}", hex::encode(t.info_hash()));
+            println!("Piece Length: {}", t.info().plength);
             println!("Piece Hashes:");
-            for hash in t.info.pieces.0 {
+            for hash in &t.info().pieces.0 {
                 println!("\t{}", hex::encode(hash));
             }
         }
         Command::Peers { torrent } => {
             let torrent_f = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent = serde_bencode::from_bytes(&torrent_f).context("parse torrent file")?;
-
-            let length = if let torrent::Keys::SingleFile { length } = t.info.keys {
-                length
-            } else {
-                panic!("key should be either singlefile or multifile!");
-            };
-
-            let request = TrackerRequest {
-                // info_hash: t.info_hash().into(),
-                // peer_id: Uuid::new_v4().into(),
-                peer_id: nanoid!(20),
-                ip: None,
-                port: 6969,
-                uploaded: 0,
-                downloaded: 0,
-                left: length,
-                event: None,
-                compact: 1,
-            };
-            t.info_hash();
-
-            let tracker_url = format!(
-                "{}?{}&info_hash={}",
-                t.announce,
-                request.http_query_params(),
-                urlencoded(&t.info_hash())
-            );
-            let response = reqwest::get(tracker_url)
-                .await
-                .context("tracker url response")?;
-            let response = response.bytes().await.context("get response bytes")?;
-
-            let tracker_resp: TrackerResponse =
-                serde_bencode::from_bytes(&response).context("deserialize response struct")?;
-            match tracker_resp.resp_type {
-                bittorrent_rs::tracker::ResponseType::Ok { interval: _, peers } => {
-                    peers.0.iter().for_each(|x| println!("{}", x));
-                }
-                bittorrent_rs::tracker::ResponseType::Err { fail_reason } => {
-                    bail!("{}", fail_reason);
-                }
-            }
+            let t = Torrent::from_bytes(&torrent_f).context("parse torrent file")?;
+
+            let peer_id = nanoid!(20);
+            let peers = tracker_peers(
+                &mut TrackerList::new(&t),
+                t.info_hash(),
+                &peer_id,
+                PEER_PORT,
+                t.info().length(),
+            )
+            .await?;
+            peers.iter().for_each(|x| println!("{}", x));
         }
         Command::Handshake { torrent, peer } => {
             let torrent_f = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent = serde_bencode::from_bytes(&torrent_f).context("parse torrent file")?;
+            let t = Torrent::from_bytes(&torrent_f).context("parse torrent file")?;
 
             let info_hash = t.info_hash();
             let peer_id = nanoid!(20).into_bytes().try_into().unwrap();
-            let mut handshake = Handshake::new(info_hash, peer_id);
+            let handshake = Handshake::new(info_hash, peer_id);
 
-            let peer = peer.parse::<SocketAddrV4>().context("parsing peer")?;
+            let peer = peer.parse::<SocketAddr>().context("parsing peer")?;
             let mut peer = TcpStream::connect(peer).await?;
 
-            let handshake_b = handshake.as_bytes_mut();
-            peer.write_all(handshake_b).await?;
-            peer.read_exact(handshake_b).await?;
+            let mut handshake_b = handshake.to_bytes();
+            peer.write_all(&handshake_b).await?;
+            peer.read_exact(&mut handshake_b).await?;
+            let handshake = Handshake::from_bytes(&handshake_b);
 
             assert_eq!(&handshake.msg, b"BitTorrent protocol");
             println!("Peer ID: {}", hex::encode(handshake.peer_id));
@@ -168,303 +210,389 @@ async fn main() -> anyhow::Result<()> {
             piece: piece_i,
         } => {
             let torrent_f = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent = serde_bencode::from_bytes(&torrent_f).context("parse torrent file")?;
-
-            let length = if let torrent::Keys::SingleFile { length } = t.info.keys {
-                length
-            } else {
-                panic!("key should be either singlefile or multifile!");
-            };
-            let request = TrackerRequest {
-                // info_hash: t.info_hash().into(),
-                // peer_id: Uuid::new_v4().into(),
-                peer_id: nanoid!(20),
-                ip: None,
-                port: 6969,
-                uploaded: 0,
-                downloaded: 0,
-                left: length,
-                event: None,
-                compact: 1,
+            let t = Torrent::from_bytes(&torrent_f).context("parse torrent file")?;
+
+            let peer_id = nanoid!(20);
+            let peers = tracker_peers(
+                &mut TrackerList::new(&t),
+                t.info_hash(),
+                &peer_id,
+                PEER_PORT,
+                t.info().length(),
+            )
+            .await?;
+
+            // Only the range of the piece maps onto the output file.
+            let piece = FileSpan {
+                path: output.clone(),
+                offset: piece_i as u64 * t.info().plength,
+                length: t.info().piece_size(piece_i),
             };
+            let storage = Arc::new(FileStorage::create(t.info(), vec![piece])?);
+            let swarm = Swarm::new(t, peer_id.into_bytes().try_into().unwrap(), storage);
+            swarm.download_piece(peers, piece_i).await?;
 
-            let tracker_url = format!(
-                "{}?{}&info_hash={}",
-                t.announce,
-                request.http_query_params(),
-                urlencoded(&t.info_hash())
-            );
-            let response = reqwest::get(tracker_url)
-                .await
-                .context("tracker url response")?;
-            let response = response.bytes().await.context("get response bytes")?;
+            println!("Piece {piece_i} downloaded to {}.", output.display());
+        }
+        Command::Download {
+            output,
+            torrent,
+            resume,
+            seed,
+            port,
+        } => {
+            let torrent_f = std::fs::read(torrent).context("read torrent file")?;
+            let t = Torrent::from_bytes(&torrent_f).context("parse torrent file")?;
 
-            let tracker_resp: TrackerResponse =
-                serde_bencode::from_bytes(&response).context("deserialize response struct")?;
+            download(t, &output, resume.as_deref(), port, seed).await?;
+            println!("File downloaded to {}.", output.display());
+        }
+        Command::Verify { torrent, path } => {
+            let torrent_f = std::fs::read(torrent).context("read torrent file")?;
+            let t = Torrent::from_bytes(&torrent_f).context("parse torrent file")?;
 
-            let mut rng = thread_rng();
-            let peers_vec = match tracker_resp.resp_type {
-                bittorrent_rs::tracker::ResponseType::Ok { interval: _, peers } => peers.0,
-                bittorrent_rs::tracker::ResponseType::Err { fail_reason } => {
-                    bail!("{}", fail_reason);
-                }
-            };
-            let peer = peers_vec
-                .choose(&mut rng)
-                .expect("peers should be returned");
-
-            let mut peer = TcpStream::connect(peer).await.context("connect to peer")?;
-            let peer_id = request.peer_id.into_bytes().try_into().unwrap();
-
-            // Handshake
-            let mut handshake = Handshake::new(t.info_hash(), peer_id);
-            let handshake_b = handshake.as_bytes_mut();
-            peer.write_all(handshake_b).await?;
-            peer.read_exact(handshake_b).await?;
-            assert_eq!(&handshake.msg, b"BitTorrent protocol");
+            let layout = t.info().layout(&path)?;
+            let report = tokio::task::spawn_blocking(move || verify::verify(t.info(), &layout))
+                .await
+                .context("verify task")?;
 
-            let mut peer = tokio_util::codec::Framed::new(peer, MessageCodec);
+            for (piece_i, status) in report.pieces.iter().enumerate() {
+                if *status != Status::Complete {
+                    println!("Piece {piece_i}: {status:?}");
+                }
+            }
+            for (file, status) in &report.files {
+                println!("{}: {status:?}", file.path.display());
+            }
+            println!(
+                "{}/{} pieces complete, {} missing, {} corrupt.",
+                report.count(Status::Complete),
+                report.pieces.len(),
+                report.count(Status::Missing),
+                report.count(Status::Corrupt),
+            );
+            if !report.is_complete() {
+                std::process::exit(1);
+            }
+        }
+        Command::Create {
+            output,
+            path,
+            announce,
+            comment,
+            private,
+            web_seeds,
+            piece_length,
+        } => {
+            let mut builder = TorrentBuilder::new(path).private(private);
+            for tier in announce {
+                builder = builder.tier(tier.split(',').map(str::to_string).collect());
+            }
+            if let Some(comment) = comment {
+                builder = builder.comment(comment);
+            }
+            for url in web_seeds {
+                builder = builder.web_seed(url);
+            }
+            if let Some(piece_length) = piece_length {
+                builder = builder.piece_length(piece_length);
+            }
 
-            // Wait for bitfield msg
-            let bitfield = peer
-                .next()
+            let t = tokio::task::spawn_blocking(move || builder.build())
                 .await
-                .expect("peer always sends a bitfields")
-                .context("peer msg was invalid")?;
-            assert_eq!(bitfield.tag, MessageTag::Bitfield);
-
-            // Send interested msg
-            peer.send(Message {
-                tag: MessageTag::Interested,
-                payload: Vec::new(),
-            })
-            .await
-            .context("send interested msg")?;
-
-            // Wait for unchoke msg
-            let unchoke = peer
-                .next()
+                .context("hash task")??;
+            let torrent_f = t.to_bytes().context("encode torrent")?;
+            tokio::fs::write(&output, torrent_f)
                 .await
-                .expect("peer always sends an unchoke")
-                .context("peer msg was invalid")?;
-            assert_eq!(unchoke.tag, MessageTag::Unchoke);
-
-            let (req_tx, mut req_rx) = tokio::sync::mpsc::channel(200);
-            let (resp_tx, resp_rx) = tokio::sync::mpsc::channel(200);
-            let h = tokio::spawn(download_piece((req_tx, resp_rx), t, piece_i));
-
-            while let Some(msg) = req_rx.recv().await {
-                peer.send(msg).await?;
-                let resp = peer.next().await.unwrap()?;
-                resp_tx.send(resp).await?;
-            }
+                .context("write torrent file")?;
 
-            let all_blocks = h.await??;
+            println!("Info Hash: {}", hex::encode(t.info_hash()));
+            println!("Torrent written to {}.", output.display());
+        }
+        Command::Scrape { torrents } => {
+            let mut ts = Vec::with_capacity(torrents.len());
+            for torrent in torrents {
+                let torrent_f = std::fs::read(&torrent)
+                    .with_context(|| format!("read torrent file {}", torrent.display()))?;
+                let t = Torrent::from_bytes(&torrent_f).context("parse torrent file")?;
+                ts.push(t);
+            }
 
-            tokio::fs::write(&output, all_blocks)
-                .await
-                .context("write out downloaded piece")?;
+            // One request per tracker, covering every torrent that lists it.
+            let mut by_tracker: BTreeMap<String, Vec<[u8; 20]>> = BTreeMap::new();
+            for t in &ts {
+                for url in TrackerList::new(t).tiers().iter().flatten() {
+                    by_tracker
+                        .entry(url.clone())
+                        .or_default()
+                        .push(t.info_hash());
+                }
+            }
+            let mut results = HashMap::new();
+            for (url, info_hashes) in &by_tracker {
+                results.insert(url, tracker::scrape(url, info_hashes).await);
+            }
 
-            println!("Piece {piece_i} downloaded to {}.", output.display());
+            for t in &ts {
+                let info_hash = t.info_hash();
+                println!("{} ({}):", t.info().name, hex::encode(info_hash));
+                for url in TrackerList::new(t).tiers().iter().flatten() {
+                    match &results[url] {
+                        Ok(stats) => match stats.get(&info_hash) {
+                            Some(s) => println!(
+                                "\t{url}: {} seeders, {} leechers, {} completed",
+                                s.complete, s.incomplete, s.downloaded
+                            ),
+                            None => println!("\t{url}: unknown torrent"),
+                        },
+                        Err(e) => println!("\t{url}: {e:#}"),
+                    }
+                }
+            }
         }
-        Command::Download { output, torrent } => {
-            let torrent_f = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent = serde_bencode::from_bytes(&torrent_f).context("parse torrent file")?;
-
-            let length = if let torrent::Keys::SingleFile { length } = t.info.keys {
-                length
+        Command::Tracker {
+            command:
+                TrackerCommand::Serve {
+                    http,
+                    udp,
+                    interval,
+                    allow,
+                },
+        } => {
+            let whitelist = if allow.is_empty() {
+                None
             } else {
-                panic!("key should be either singlefile or multifile!");
-            };
-            let request = TrackerRequest {
-                // info_hash: t.info_hash().into(),
-                // peer_id: Uuid::new_v4().into(),
-                peer_id: nanoid!(20),
-                ip: None,
-                port: 6969,
-                uploaded: 0,
-                downloaded: 0,
-                left: length,
-                event: None,
-                compact: 1,
+                let mut whitelist = HashSet::new();
+                for info_hash in &allow {
+                    let info_hash = hex::decode(info_hash)
+                        .ok()
+                        .and_then(|h| <[u8; 20]>::try_from(h).ok())
+                        .with_context(|| format!("invalid info hash {info_hash}"))?;
+                    whitelist.insert(info_hash);
+                }
+                Some(whitelist)
             };
 
-            let tracker_url = format!(
-                "{}?{}&info_hash={}",
-                t.announce,
-                request.http_query_params(),
-                urlencoded(&t.info_hash())
-            );
-            let response = reqwest::get(tracker_url)
-                .await
-                .context("tracker url response")?;
-            let response = response.bytes().await.context("get response bytes")?;
-
-            let tracker_resp: TrackerResponse =
-                serde_bencode::from_bytes(&response).context("deserialize response struct")?;
-
-            let mut rng = thread_rng();
-            let peers_vec = match tracker_resp.resp_type {
-                bittorrent_rs::tracker::ResponseType::Ok { interval: _, peers } => peers.0,
-                bittorrent_rs::tracker::ResponseType::Err { fail_reason } => {
-                    bail!("{}", fail_reason);
+            let http = match http {
+                Some(addr) => {
+                    let listener = TcpListener::bind(addr).await.context("bind http")?;
+                    println!("HTTP tracker on http://{}/announce", listener.local_addr()?);
+                    Some(listener)
                 }
+                None => None,
             };
-
-            let peer = peers_vec
-                .choose(&mut rng)
-                .expect("peers should be returned");
-
-            let mut peer = TcpStream::connect(peer).await.context("connect to peer")?;
-            let peer_id = request.peer_id.into_bytes().try_into().unwrap();
-
-            // Handshake
-            let mut handshake = Handshake::new(t.info_hash(), peer_id);
-            let handshake_b = handshake.as_bytes_mut();
-            peer.write_all(handshake_b).await?;
-            peer.read_exact(handshake_b).await?;
-
-            assert_eq!(&handshake.msg, b"BitTorrent protocol");
-
-            let mut peer = tokio_util::codec::Framed::new(peer, MessageCodec);
-
-            // Wait for bitfield msg
-            let bitfield = peer
-                .next()
-                .await
-                .expect("peer always sends a bitfields")
-                .context("peer msg was invalid")?;
-            assert_eq!(bitfield.tag, MessageTag::Bitfield);
-
-            // Send interested msg
-            peer.send(Message {
-                tag: MessageTag::Interested,
-                payload: Vec::new(),
-            })
-            .await
-            .context("send interested msg")?;
-
-            // Wait for unchoke msg
-            let unchoke = peer
-                .next()
-                .await
-                .expect("peer always sends an unchoke")
-                .context("peer msg was invalid")?;
-            assert_eq!(unchoke.tag, MessageTag::Unchoke);
-
-            let pieces = &t.info.pieces.0;
-            let mut handles = Vec::with_capacity(pieces.len());
-            let mut resp_txes = Vec::with_capacity(pieces.len());
-            let (req_tx, mut req_rx) = tokio::sync::mpsc::channel(200);
-            for (piece_i, _) in pieces.iter().enumerate() {
-                let (resp_tx, resp_rx) = tokio::sync::mpsc::channel(200);
-                let h = tokio::spawn(download_piece(
-                    (req_tx.clone(), resp_rx),
-                    t.clone(),
-                    piece_i,
-                ));
-                handles.push(h);
-                resp_txes.push(resp_tx);
+            let udp = match udp {
+                Some(addr) => {
+                    let socket = UdpSocket::bind(addr).await.context("bind udp")?;
+                    println!("UDP tracker on udp://{}/announce", socket.local_addr()?);
+                    Some(socket)
+                }
+                None => None,
+            };
+            let server = Arc::new(Server::new(Duration::from_secs(interval), whitelist));
+            server.run(http, udp).await?;
+        }
+        Command::Magnet { output, link } => {
+            let magnet: Magnet = link.parse().context("parse magnet link")?;
+            if magnet.trackers.is_empty() {
+                bail!("magnet link has no trackers");
             }
+            let mut trackers = TrackerList::from_tiers(
+                magnet.trackers.iter().map(|tr| vec![tr.clone()]).collect(),
+            );
 
-            drop(req_tx);
-            while let Some(msg) = req_rx.recv().await {
-                peer.send(msg).await?;
-                let resp = peer.next().await.unwrap()?;
-                if resp.tag == MessageTag::Piece {
-                    let piece = Piece::ref_from_bytes(&resp.payload[..]).unwrap();
-                    let idx = piece.index() as usize;
-                    resp_txes[idx].send(resp).await?;
+            let peer_id = nanoid!(20);
+            let peers = tracker_peers(
+                &mut trackers,
+                magnet.info_hash,
+                &peer_id,
+                PEER_PORT,
+                MAGNET_LEFT,
+            )
+            .await?;
+
+            let mut metadata = None;
+            for peer in peers {
+                match magnet
+                    .fetch_metadata(peer, peer_id.clone().into_bytes().try_into().unwrap())
+                    .await
+                {
+                    Ok(m) => {
+                        metadata = Some(m);
+                        break;
+                    }
+                    Err(e) => eprintln!("fetch metadata from {peer}: {e:#}"),
                 }
             }
+            let t = magnet.to_torrent(metadata.context("no peer sent the metadata")?)?;
 
-            let mut all_blocks = Vec::with_capacity(handles.len());
-            for h in handles {
-                let piece = h.await??;
-                piece.into_iter().for_each(|p| all_blocks.push(p));
+            println!("Tracker URL: {}", t.announce);
+            println!("Length: {}", t.info().length());
+            println!("Info Hash: {}", hex::encode(magnet.info_hash));
+            println!("Piece Length: {}", t.info().plength);
+            println!("Piece Hashes:");
+            for hash in &t.info().pieces.0 {
+                println!("\t{}", hex::encode(hash));
             }
 
-            tokio::fs::write(&output, all_blocks)
-                .await
-                .context("write out downloaded piece")?;
-
-            println!("File downloaded to {}.", output.display());
+            if let Some(output) = output {
+                download(t, &output, None, PEER_PORT, false).await?;
+                println!("File downloaded to {}.", output.display());
+            }
         }
     }
     Ok(())
 }
 
-type Channel = (Sender<Message>, Receiver<Message>);
-async fn download_piece(c: Channel, t: Torrent, piece_i: usize) -> anyhow::Result<Vec<u8>> {
-    let (tx, mut rx) = c;
-    let length = if let torrent::Keys::SingleFile { length } = t.info.keys {
-        length
-    } else {
-        panic!("key should be either singlefile or multifile!");
-    };
-    let piece_hash = &t.info.pieces.0[piece_i];
-
-    // last piece can be smaller than the plength
-    let piece_size = if piece_i == t.info.pieces.0.len() - 1 {
-        let md = length % t.info.plength;
-        if md == 0 {
-            t.info.plength
-        } else {
-            md
-        }
-    } else {
-        t.info.plength
-    };
-    let nblocks = piece_size.div_ceil(BLOCK_MAX);
-
-    let mut all_blocks = Vec::with_capacity(piece_size as usize);
-    for block in 0..nblocks {
-        let block_size = if block == nblocks - 1 {
-            let md = piece_size % BLOCK_MAX;
-            if md == 0 {
-                BLOCK_MAX
-            } else {
-                md
-            }
-        } else {
-            BLOCK_MAX
-        };
-        let mut request = Request::new(
-            piece_i as u32,
-            (block * BLOCK_MAX) as u32,
-            block_size as u32,
-        );
-
-        let request_bytes = Vec::from(request.as_bytes_mut());
-
-        tx.send(Message {
-            tag: MessageTag::Request,
-
-            payload: request_bytes,
-        })
-        .await
-        .with_context(|| format!("send request for block {block}"))?;
+/// Asks the trackers for peers of the torrent with `info_hash`, announcing that we accept peers on
+/// `port`.
+async fn tracker_peers(
+    trackers: &mut TrackerList,
+    info_hash: [u8; 20],
+    peer_id: &str,
+    port: u16,
+    left: u64,
+) -> anyhow::Result<Vec<SocketAddr>> {
+    let request = TrackerRequest::new(info_hash, peer_id.to_string(), port, left);
+
+    let tracker_resp = trackers.announce(&request).await?;
+    match tracker_resp.resp_type {
+        ResponseType::Ok { peers, peers6, .. } => Ok(peers.0.into_iter().chain(peers6.0).collect()),
+        ResponseType::Err { fail_reason } => bail!("{}", fail_reason),
+    }
+}
 
-        let piece = rx.recv().await.expect("peer always sends a piece");
+/// Listens for peers on `port`, dual-stack where the system allows it. Falls back to a port the
+/// system picks when `port` is taken, e.g. by another client.
+async fn listen(port: u16) -> anyhow::Result<TcpListener> {
+    async fn bind(port: u16) -> std::io::Result<TcpListener> {
+        match TcpListener::bind((Ipv6Addr::UNSPECIFIED, port)).await {
+            Ok(listener) => Ok(listener),
+            Err(_) => TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await,
+        }
+    }
 
-        assert_eq!(piece.tag, MessageTag::Piece);
+    match bind(port).await {
+        Err(e) if port != 0 => {
+            let listener = bind(0).await.context("listen for peers")?;
+            eprintln!(
+                "listen on port {port}: {e}, using port {} instead",
+                listener.local_addr()?.port()
+            );
+            Ok(listener)
+        }
+        res => res.context("listen for peers"),
+    }
+}
 
-        let piece = Piece::ref_from_bytes(&piece.payload[..])
-            .expect("always get all Piece response fields from peer");
+/// Resolves on Ctrl-C, or when the process is asked to terminate where there is such a thing.
+async fn shutdown() -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).context("listen for SIGTERM")?;
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res.context("listen for Ctrl-C")?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.context("listen for Ctrl-C")?;
+    Ok(())
+}
 
-        assert_eq!(piece.index() as usize, piece_i);
-        assert_eq!(piece.begin() as u64, block * BLOCK_MAX);
-        assert_eq!(piece.block().len(), block_size as usize);
+/// Downloads every piece of `t` from the swarm into the files under `output`, uploading to peers
+/// that connect on `port` meanwhile, and afterwards as well with `seed`.
+///
+/// Data already at `output` is hash-checked first, or trusted from the fast-resume file at
+/// `resume` when it still matches, and only the missing pieces are downloaded.
+async fn download(
+    t: Torrent,
+    output: &Path,
+    resume: Option<&Path>,
+    port: u16,
+    seed: bool,
+) -> anyhow::Result<()> {
+    let peer_id = nanoid!(20);
+    let info_hash = t.info_hash();
+    let npieces = t.info().pieces.0.len();
+    let plength = t.info().plength;
+    let layout = t.info().layout(output)?;
+    let existing = layout.iter().any(|f| f.path.exists());
+    let fast_resume = match resume {
+        Some(path) => FastResume::load(path)?,
+        None => None,
+    };
 
-        all_blocks.extend(piece.block());
+    let storage = Arc::new(FileStorage::create(t.info(), layout.clone())?);
+    let listener = listen(port).await?;
+    let port = listener.local_addr()?.port();
+    let mut announcer = Announcer::new(&t, peer_id.clone(), port);
+    let swarm = Swarm::new(t, peer_id.clone().into_bytes().try_into().unwrap(), storage);
+
+    let mut peers = Vec::new();
+    if existing {
+        match fast_resume
+            .as_ref()
+            .and_then(|r| r.validate(info_hash, &layout, plength, npieces))
+        {
+            Some((have, false)) => swarm.resume(&have),
+            Some((have, true)) => {
+                swarm.recheck(&have).await?;
+            }
+            None => {
+                swarm.recheck(&Bitfield::new(npieces)).await?;
+            }
+        }
+        println!("Resuming with {}/{npieces} pieces.", swarm.have().count());
+        peers.extend(fast_resume.iter().flat_map(|r| r.peers()));
+    }
+    for peer in announcer.start(&swarm).await? {
+        if !peers.contains(&peer) {
+            peers.push(peer);
+        }
     }
-    assert_eq!(all_blocks.len(), piece_size as usize);
-
-    let mut hasher = Sha1::new();
-    hasher.update(&all_blocks);
-    let hash: [u8; 20] = hasher.finalize().into();
-    assert_eq!(&hash, piece_hash);
 
-    println!("task done");
-    Ok(all_blocks)
+    let save = || -> anyhow::Result<()> {
+        if let Some(path) = resume {
+            swarm.flush()?;
+            FastResume::capture(info_hash, &swarm.have(), &layout, &peers)?.save(path)?;
+        }
+        Ok(())
+    };
+    let swarms = [&swarm];
+    if seed {
+        println!("Seeding on port {port} once complete, interrupt to stop.");
+    }
+    let res = tokio::select! {
+        res = async {
+            if seed {
+                swarm.seed(peers.clone()).await
+            } else {
+                swarm.download(peers.clone()).await
+            }
+        } => res,
+        res = swarm::listen(listener, &swarms) => res,
+        _ = async {
+            let mut interval = tokio::time::interval(RESUME_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(e) = save() {
+                    eprintln!("save fast-resume file: {e:#}");
+                }
+            }
+        } => unreachable!(),
+        _ = announcer.run(&swarm) => unreachable!(),
+        res = shutdown() => res.and_then(|()| {
+            if *swarm.done().borrow() {
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!("interrupted"))
+            }
+        }),
+    };
+    announcer.stop(&swarm).await;
+    save()?;
+    res
 }
@@ -1,9 +1,13 @@
-use anyhow::Context;
-use bytes::{Buf, BufMut, BytesMut};
-use num_enum::{IntoPrimitive, TryFromPrimitive};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use num_enum::TryFromPrimitive;
 use tokio_util::codec::{Decoder, Encoder};
 
-#[repr(packed)]
+use crate::bitfield::Bitfield;
+
+/// Size of the blocks we request from peers, the de facto maximum every client serves.
+pub const BLOCK_MAX: u64 = 1 << 14;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Handshake {
     pub length: u8,
     pub msg: [u8; 19],
@@ -13,6 +17,9 @@ pub struct Handshake {
 }
 
 impl Handshake {
+    /// Size of a handshake on the wire.
+    pub const LEN: usize = 68;
+
     pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
         Handshake {
             length: 19,
@@ -23,9 +30,33 @@ impl Handshake {
         }
     }
 
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        let bytes = self as *mut Handshake as *mut [u8; std::mem::size_of::<Handshake>()];
-        let bytes: &mut [u8; std::mem::size_of::<Handshake>()] = unsafe { &mut *bytes };
+    /// Sets the reserved bit advertising support for the extension protocol (BEP 10).
+    pub fn with_extensions(mut self) -> Self {
+        self.reserved[5] |= 0x10;
+        self
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[5] & 0x10 != 0
+    }
+
+    pub fn from_bytes(bytes: &[u8; Self::LEN]) -> Self {
+        Handshake {
+            length: bytes[0],
+            msg: bytes[1..20].try_into().unwrap(),
+            reserved: bytes[20..28].try_into().unwrap(),
+            info_hash: bytes[28..48].try_into().unwrap(),
+            peer_id: bytes[48..68].try_into().unwrap(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut bytes = [0; Self::LEN];
+        bytes[0] = self.length;
+        bytes[1..20].copy_from_slice(&self.msg);
+        bytes[20..28].copy_from_slice(&self.reserved);
+        bytes[28..48].copy_from_slice(&self.info_hash);
+        bytes[48..68].copy_from_slice(&self.peer_id);
         bytes
     }
 }
@@ -42,103 +73,222 @@ pub enum MessageTag {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    Extended = 20,
 }
 
-#[derive(Debug)]
-pub struct Message {
-    pub tag: MessageTag,
-    pub payload: Vec<u8>,
-}
-
-#[repr(C)]
-pub struct Request {
-    index: [u8; 4],
-    begin: [u8; 4],
-    length: [u8; 4],
+/// A message of the peer wire protocol, with its payload parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerMessage {
+    /// An empty frame, sent to keep an otherwise idle connection open.
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    /// Holds every bit the peer sent, the receiver knows how many pieces there really are.
+    Bitfield(Bitfield),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// The block shares the buffer the frame was read into rather than being copied out.
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Bytes,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// A message of the extension protocol (BEP 10), `id` 0 being the extension handshake.
+    Extended {
+        id: u8,
+        payload: Bytes,
+    },
+    /// A message we do not implement, such as `Port` or the fast extension.
+    Unknown {
+        id: u8,
+        payload: Bytes,
+    },
 }
 
-impl Request {
-    pub fn new(index: u32, begin: u32, length: u32) -> Self {
-        Self {
-            index: index.to_be_bytes(),
-            begin: begin.to_be_bytes(),
-            length: length.to_be_bytes(),
-        }
-    }
-
-    pub fn index(&self) -> u32 {
-        u32::from_be_bytes(self.index)
+impl PeerMessage {
+    /// Parses the payload of a message with tag `id`. Messages we do not know about come back as
+    /// `Unknown`, known ones with a payload of the wrong size are an error.
+    fn parse(id: u8, payload: Bytes) -> std::io::Result<Self> {
+        let Ok(tag) = MessageTag::try_from(id) else {
+            return Ok(PeerMessage::Unknown { id, payload });
+        };
+        let msg = match (tag, payload.len()) {
+            (MessageTag::Choke, 0) => PeerMessage::Choke,
+            (MessageTag::Unchoke, 0) => PeerMessage::Unchoke,
+            (MessageTag::Interested, 0) => PeerMessage::Interested,
+            (MessageTag::NotInterested, 0) => PeerMessage::NotInterested,
+            (MessageTag::Have, 4) => PeerMessage::Have(be_u32(&payload, 0)),
+            (MessageTag::Bitfield, n) => {
+                PeerMessage::Bitfield(Bitfield::from_bytes(payload.to_vec(), n * 8))
+            }
+            (MessageTag::Request, 12) => PeerMessage::Request {
+                index: be_u32(&payload, 0),
+                begin: be_u32(&payload, 1),
+                length: be_u32(&payload, 2),
+            },
+            (MessageTag::Piece, 8..) => PeerMessage::Piece {
+                index: be_u32(&payload, 0),
+                begin: be_u32(&payload, 1),
+                block: payload.slice(8..),
+            },
+            (MessageTag::Cancel, 12) => PeerMessage::Cancel {
+                index: be_u32(&payload, 0),
+                begin: be_u32(&payload, 1),
+                length: be_u32(&payload, 2),
+            },
+            (MessageTag::Extended, 1..) => PeerMessage::Extended {
+                id: payload[0],
+                payload: payload.slice(1..),
+            },
+            (tag, n) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{tag:?} message with a payload of {n} bytes"),
+                ))
+            }
+        };
+        Ok(msg)
     }
 
-    pub fn begin(&self) -> u32 {
-        u32::from_be_bytes(self.begin)
-    }
-
-    pub fn length(&self) -> u32 {
-        u32::from_be_bytes(self.length)
-    }
-
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
-        // Safety: Handshake is a POD with repr(c)
-        let bytes: &mut [u8; std::mem::size_of::<Self>()] = unsafe { &mut *bytes };
-        bytes
+    /// Writes the tag and payload of the message.
+    fn write(&self, dst: &mut BytesMut) {
+        match self {
+            PeerMessage::KeepAlive => {}
+            PeerMessage::Choke => dst.put_u8(MessageTag::Choke as u8),
+            PeerMessage::Unchoke => dst.put_u8(MessageTag::Unchoke as u8),
+            PeerMessage::Interested => dst.put_u8(MessageTag::Interested as u8),
+            PeerMessage::NotInterested => dst.put_u8(MessageTag::NotInterested as u8),
+            PeerMessage::Have(index) => {
+                dst.put_u8(MessageTag::Have as u8);
+                dst.put_u32(*index);
+            }
+            PeerMessage::Bitfield(bitfield) => {
+                dst.put_u8(MessageTag::Bitfield as u8);
+                dst.extend_from_slice(bitfield.as_bytes());
+            }
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } => {
+                dst.put_u8(MessageTag::Request as u8);
+                dst.put_u32(*index);
+                dst.put_u32(*begin);
+                dst.put_u32(*length);
+            }
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                dst.put_u8(MessageTag::Piece as u8);
+                dst.put_u32(*index);
+                dst.put_u32(*begin);
+                dst.extend_from_slice(block);
+            }
+            PeerMessage::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                dst.put_u8(MessageTag::Cancel as u8);
+                dst.put_u32(*index);
+                dst.put_u32(*begin);
+                dst.put_u32(*length);
+            }
+            PeerMessage::Extended { id, payload } => {
+                dst.put_u8(MessageTag::Extended as u8);
+                dst.put_u8(*id);
+                dst.extend_from_slice(payload);
+            }
+            PeerMessage::Unknown { id, payload } => {
+                dst.put_u8(*id);
+                dst.extend_from_slice(payload);
+            }
+        }
     }
 }
 
-// impl From<Message> for Request {
-//     fn from(value: Message) -> Self {
-//         assert_eq!(value.tag, MessageTag::Request);
-//         assert_eq!(value.payload.len(), 12);
-//         Request {
-//             index: value.payload[..4].try_into().unwrap(),
-//             begin: value.payload[4..8].try_into().unwrap(),
-//             length: value.payload[8..12].try_into().unwrap(),
-//         }
-//     }
-// }
-
-#[repr(C)]
-pub struct Piece<T: ?Sized = [u8]> {
-    index: [u8; 4],
-    begin: [u8; 4],
-    block: T,
+/// The `i`th big-endian `u32` of `bytes`.
+fn be_u32(bytes: &[u8], i: usize) -> u32 {
+    u32::from_be_bytes(bytes[4 * i..4 * i + 4].try_into().unwrap())
 }
 
-impl Piece {
-    pub fn index(&self) -> u32 {
-        u32::from_be_bytes(self.index)
-    }
+/// Largest payloads the codec accepts, per message type, so a peer cannot make us buffer
+/// arbitrary amounts of data. Messages with a fixed size are held to that size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameLimits {
+    /// One bit per piece of the torrent.
+    pub bitfield: usize,
+    /// A block and its 8 byte header.
+    pub piece: usize,
+    /// Extension messages, such as metadata pieces.
+    pub extended: usize,
+    /// Messages we do not know about.
+    pub unknown: usize,
+}
 
-    pub fn begin(&self) -> u32 {
-        u32::from_be_bytes(self.begin)
+impl FrameLimits {
+    /// Limits for a torrent with `pieces` pieces.
+    pub fn for_pieces(pieces: usize) -> Self {
+        FrameLimits {
+            bitfield: pieces.div_ceil(8),
+            ..Self::default()
+        }
     }
 
-    pub fn block(&self) -> &[u8] {
-        &self.block
+    /// Largest payload of a message with tag `id`.
+    fn payload(&self, id: u8) -> usize {
+        match MessageTag::try_from(id) {
+            Ok(MessageTag::Choke) | Ok(MessageTag::Unchoke) => 0,
+            Ok(MessageTag::Interested) | Ok(MessageTag::NotInterested) => 0,
+            Ok(MessageTag::Have) => 4,
+            Ok(MessageTag::Request | MessageTag::Cancel) => 12,
+            Ok(MessageTag::Bitfield) => self.bitfield,
+            Ok(MessageTag::Piece) => self.piece,
+            Ok(MessageTag::Extended) => self.extended,
+            Err(_) => self.unknown,
+        }
     }
+}
 
-    const PIECE_LEAD: usize = std::mem::size_of::<Piece<()>>();
-
-    pub fn ref_from_bytes(data: &[u8]) -> Option<&Self> {
-        if data.len() < Self::PIECE_LEAD {
-            return None;
+impl Default for FrameLimits {
+    /// Limits for when the torrent is not known yet, such as while fetching its metadata.
+    fn default() -> Self {
+        FrameLimits {
+            // Enough for 8M pieces.
+            bitfield: 1 << 20,
+            piece: 8 + BLOCK_MAX as usize,
+            extended: 1 << 20,
+            unknown: 1 << 16,
         }
-        let n = data.len();
-
-        let piece = &data[..n - Self::PIECE_LEAD] as *const [u8] as *const Piece;
-        // Safety: Piece is a POD with repr(c), _and_ the fat pointer data length is the length of
-        // the trailing DST field (thanks to the PIECE_LEAD offset).
-        Some(unsafe { &*piece })
     }
 }
 
-pub struct MessageCodec;
+#[derive(Debug, Clone, Default)]
+pub struct MessageCodec {
+    limits: FrameLimits,
+}
 
-const MAX: usize = 1 << 16;
+impl MessageCodec {
+    pub fn new(limits: FrameLimits) -> Self {
+        MessageCodec { limits }
+    }
+}
 
 impl Decoder for MessageCodec {
-    type Item = Message;
+    type Item = PeerMessage;
     type Error = std::io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
@@ -153,17 +303,25 @@ impl Decoder for MessageCodec {
         let length = u32::from_be_bytes(length_bytes) as usize;
 
         if length == 0 {
-            // keepalive message, ignore it
             src.advance(4);
-            return self.decode(src);
+            return Ok(Some(PeerMessage::KeepAlive));
+        }
+
+        if src.len() < 5 {
+            // Not enough data to read the message tag.
+            return Ok(None);
         }
 
         // Check that the length is not too large to avoid a denial of
         // service attack where the server runs out of memory.
-        if length > MAX {
+        let id = src[4];
+        if length - 1 > self.limits.payload(id) {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
-                format!("Frame of length {} is too large.", length),
+                format!(
+                    "Frame of length {} is too large for message {}.",
+                    length, id
+                ),
             ));
         }
 
@@ -178,44 +336,220 @@ impl Decoder for MessageCodec {
             // frame.
             return Ok(None);
         }
-        let tag = MessageTag::try_from(src[4])
-            .context("convert byte to tag")
-            .unwrap();
+        // Split the frame off src. The payload keeps pointing into the
+        // same buffer, so blocks are not copied.
+        let mut payload = src.split_to(4 + length).freeze();
+        payload.advance(5);
 
-        // Use advance to modify src such that it no longer contains
-        // this frame.
-        let data = src[5..4 + length].to_vec();
-        src.advance(4 + length);
-
-        // Convert data to Message
-        Ok(Some(Message { tag, payload: data }))
+        PeerMessage::parse(id, payload).map(Some)
     }
 }
 
-impl Encoder<Message> for MessageCodec {
+impl Encoder<PeerMessage> for MessageCodec {
     type Error = std::io::Error;
 
-    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        // Don't send a string if it is longer than the other end will
+    fn encode(&mut self, item: PeerMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        // Write a placeholder for the length, it is only known once the message is written.
+        let start = dst.len();
+        dst.put_u32(0);
+        item.write(dst);
+        let length = dst.len() - start - 4;
+        if length == 0 {
+            // A keepalive, the placeholder already is its frame.
+            return Ok(());
+        }
+
+        // Don't send a frame if it is longer than the other end will
         // accept.
-        if item.payload.len() > MAX {
+        let id = dst[start + 4];
+        if length - 1 > self.limits.payload(id) {
+            dst.truncate(start);
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
-                format!("Frame of length {} is too large.", item.payload.len()),
+                format!("Frame of length {} is too large.", length),
             ));
         }
 
-        // Convert the length into a byte array.
         // The cast to u32 cannot overflow due to the length check above.
-        let len_slice = u32::to_be_bytes(item.payload.len() as u32 + 1 /* message tag */);
+        dst[start..start + 4].copy_from_slice(&(length as u32).to_be_bytes());
+        Ok(())
+    }
+}
 
-        // Reserve space in the buffer.
-        dst.reserve(4 /* len */ + 1 /* tag */ + item.payload.len());
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        // Write the length and string to the buffer.
-        dst.extend_from_slice(&len_slice);
-        dst.put_u8(item.tag as u8);
-        dst.extend_from_slice(&item.payload);
-        Ok(())
+    /// Decodes `frame` in one go, asserting the codec consumed all of it.
+    fn decode(codec: &mut MessageCodec, frame: &[u8]) -> std::io::Result<Option<PeerMessage>> {
+        let mut src = BytesMut::from(frame);
+        let msg = codec.decode(&mut src);
+        if msg.as_ref().is_ok_and(Option::is_some) {
+            assert!(src.is_empty());
+        }
+        msg
+    }
+
+    #[test]
+    fn handshake_round_trips() {
+        let handshake = Handshake::new([1; 20], [2; 20]).with_extensions();
+        let bytes = handshake.to_bytes();
+        assert_eq!(bytes[0], 19);
+        assert_eq!(&bytes[1..20], b"BitTorrent protocol");
+        assert_eq!(bytes[25], 0x10);
+        assert_eq!(Handshake::from_bytes(&bytes), handshake);
+        assert!(!Handshake::new([1; 20], [2; 20]).supports_extensions());
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let mut codec = MessageCodec::new(FrameLimits::for_pieces(12));
+        for msg in [
+            PeerMessage::KeepAlive,
+            PeerMessage::Choke,
+            PeerMessage::Unchoke,
+            PeerMessage::Interested,
+            PeerMessage::NotInterested,
+            PeerMessage::Have(7),
+            PeerMessage::Bitfield(Bitfield::from_bytes(vec![0xa5, 0xf0], 16)),
+            PeerMessage::Request {
+                index: 1,
+                begin: 2,
+                length: 3,
+            },
+            PeerMessage::Piece {
+                index: 1,
+                begin: 2,
+                block: Bytes::from_static(b"block"),
+            },
+            PeerMessage::Piece {
+                index: 1,
+                begin: 2,
+                block: Bytes::new(),
+            },
+            PeerMessage::Cancel {
+                index: 1,
+                begin: 2,
+                length: 3,
+            },
+            PeerMessage::Extended {
+                id: 0,
+                payload: Bytes::from_static(b"de"),
+            },
+            PeerMessage::Unknown {
+                id: 9,
+                payload: Bytes::from_static(&[0x1a, 0xe1]),
+            },
+        ] {
+            let mut frame = BytesMut::new();
+            codec.encode(msg.clone(), &mut frame).unwrap();
+            assert_eq!(
+                u32::from_be_bytes(frame[..4].try_into().unwrap()) as usize,
+                frame.len() - 4
+            );
+            assert_eq!(decode(&mut codec, &frame).unwrap(), Some(msg));
+        }
+    }
+
+    #[test]
+    fn decode_waits_for_whole_frames() {
+        let mut codec = MessageCodec::default();
+        let frame = [0, 0, 0, 0, 0, 0, 0, 5, 4, 0, 0, 0, 9, 0, 0, 0, 1, 1];
+        let mut src = BytesMut::new();
+        let mut msgs = Vec::new();
+        for &b in &frame {
+            src.put_u8(b);
+            if let Some(msg) = codec.decode(&mut src).unwrap() {
+                msgs.push(msg);
+            }
+        }
+        assert_eq!(
+            msgs,
+            [
+                PeerMessage::KeepAlive,
+                PeerMessage::Have(9),
+                PeerMessage::Unchoke
+            ]
+        );
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn keepalive_floods_are_decoded_one_by_one() {
+        let mut codec = MessageCodec::default();
+        let mut src = BytesMut::zeroed(4 << 20);
+        src.extend([0, 0, 0, 1, 1]);
+        let mut keepalives = 0;
+        loop {
+            match codec.decode(&mut src).unwrap() {
+                Some(PeerMessage::KeepAlive) => keepalives += 1,
+                msg => {
+                    assert_eq!(msg, Some(PeerMessage::Unchoke));
+                    break;
+                }
+            }
+        }
+        assert_eq!(keepalives, 1 << 20);
+    }
+
+    #[test]
+    fn unknown_ids_are_passed_through() {
+        let mut codec = MessageCodec::default();
+        // Port (BEP 5) and Have All (BEP 6).
+        assert_eq!(
+            decode(&mut codec, &[0, 0, 0, 3, 9, 0x1a, 0xe1]).unwrap(),
+            Some(PeerMessage::Unknown {
+                id: 9,
+                payload: Bytes::from_static(&[0x1a, 0xe1]),
+            })
+        );
+        assert_eq!(
+            decode(&mut codec, &[0, 0, 0, 1, 0x0e]).unwrap(),
+            Some(PeerMessage::Unknown {
+                id: 0x0e,
+                payload: Bytes::new(),
+            })
+        );
+    }
+
+    #[test]
+    fn wrong_payload_sizes_are_rejected() {
+        let mut codec = MessageCodec::default();
+        for frame in [
+            &[0, 0, 0, 2, 0, 0][..],
+            &[0, 0, 0, 4, 4, 0, 0, 0],
+            &[0, 0, 0, 6, 4, 0, 0, 0, 0, 0],
+            &[0, 0, 0, 12, 6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            &[0, 0, 0, 8, 7, 0, 0, 0, 0, 0, 0, 0],
+            &[0, 0, 0, 14, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            &[0, 0, 0, 1, 20],
+        ] {
+            let err = decode(&mut codec, frame).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData, "{frame:?}");
+        }
+    }
+
+    #[test]
+    fn frames_over_the_limits_are_rejected_from_the_header() {
+        let limits = FrameLimits::for_pieces(9);
+        assert_eq!(limits.bitfield, 2);
+        let mut codec = MessageCodec::new(limits);
+
+        // Only the length and tag have arrived, the rest is never buffered.
+        assert!(decode(&mut codec, &[0, 0, 0, 4, 5]).is_err());
+        assert!(decode(&mut codec, &[0, 0, 0, 3, 5]).unwrap().is_none());
+        let piece = (1 + 8 + BLOCK_MAX as u32 + 1).to_be_bytes();
+        assert!(decode(&mut codec, &[piece[0], piece[1], piece[2], piece[3], 7]).is_err());
+        assert!(decode(&mut codec, &[0, 0x10, 0, 2, 20]).is_err());
+        assert!(decode(&mut codec, &[0, 1, 0, 2, 99]).is_err());
+    }
+
+    #[test]
+    fn encode_refuses_frames_the_peer_would_reject() {
+        let mut codec = MessageCodec::new(FrameLimits::for_pieces(8));
+        let mut dst = BytesMut::from(&b"kept"[..]);
+        let bitfield = PeerMessage::Bitfield(Bitfield::new(9));
+        assert!(codec.encode(bitfield, &mut dst).is_err());
+        assert_eq!(&dst[..], b"kept");
     }
 }
@@ -0,0 +1,485 @@
+use std::collections::HashMap;
+
+use rand::{seq::SliceRandom, thread_rng, Rng};
+
+use crate::{bitfield::Bitfield, peer::BLOCK_MAX, torrent::Info};
+
+/// Until this many pieces are complete we pick at random instead of rarest-first, so that we
+/// quickly have something to trade with other peers.
+const RANDOM_FIRST_PIECES: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceState {
+    Missing,
+    InProgress,
+    Done,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockState {
+    Free,
+    /// Requested from this many peers, more than one only in endgame.
+    Requested(u32),
+    /// One peer's copy is being written to storage, any other copy is dropped.
+    Writing,
+    Received,
+}
+
+/// A block of a piece, the unit we request from peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Block {
+    pub piece: usize,
+    pub begin: u32,
+    pub length: u32,
+}
+
+/// Chooses which piece to download next from a peer.
+///
+/// Keeps a count of how many connected peers have each piece, fed from their `Bitfield` and
+/// `Have` messages, and picks the rarest piece the peer has with random tie-breaking. Pieces in
+/// progress are tracked block by block so several requests can be outstanding at once.
+///
+/// Once every missing block has been requested the picker enters endgame and hands out blocks
+/// that are already requested from other peers, so the last pieces do not hang on a slow peer.
+#[derive(Debug, Clone)]
+pub struct PiecePicker {
+    availability: Vec<u32>,
+    pieces: Vec<PieceState>,
+    partial: HashMap<usize, Vec<BlockState>>,
+    piece_sizes: (u64, u64),
+    done: usize,
+    endgame: bool,
+}
+
+impl PiecePicker {
+    pub fn new(info: &Info) -> Self {
+        let npieces = info.pieces.0.len();
+        let last = npieces.checked_sub(1).map_or(0, |i| info.piece_size(i));
+        PiecePicker {
+            availability: vec![0; npieces],
+            pieces: vec![PieceState::Missing; npieces],
+            partial: HashMap::new(),
+            piece_sizes: (info.plength, last),
+            done: 0,
+            endgame: false,
+        }
+    }
+
+    /// Accounts for the pieces of a peer that sent us its bitfield.
+    pub fn add_bitfield(&mut self, has: &Bitfield) {
+        for piece_i in has.iter() {
+            self.availability[piece_i] += 1;
+        }
+    }
+
+    /// Forgets the pieces of a peer, when it disconnects or replaces its bitfield.
+    pub fn remove_bitfield(&mut self, has: &Bitfield) {
+        for piece_i in has.iter() {
+            self.availability[piece_i] = self.availability[piece_i].saturating_sub(1);
+        }
+    }
+
+    /// Accounts for a `Have` message.
+    pub fn add_have(&mut self, piece_i: usize) {
+        if let Some(count) = self.availability.get_mut(piece_i) {
+            *count += 1;
+        }
+    }
+
+    pub fn availability(&self, piece_i: usize) -> u32 {
+        self.availability[piece_i]
+    }
+
+    pub fn state(&self, piece_i: usize) -> PieceState {
+        self.pieces[piece_i]
+    }
+
+    /// The pieces that are done.
+    pub fn have(&self) -> Bitfield {
+        let mut have = Bitfield::new(self.pieces.len());
+        for (piece_i, _) in self
+            .pieces
+            .iter()
+            .enumerate()
+            .filter(|(_, &s)| s == PieceState::Done)
+        {
+            have.set(piece_i);
+        }
+        have
+    }
+
+    /// Number of pieces that are not done yet.
+    pub fn remaining(&self) -> usize {
+        self.pieces.len() - self.done
+    }
+
+    pub fn piece_size(&self, piece_i: usize) -> u64 {
+        if piece_i == self.pieces.len() - 1 {
+            self.piece_sizes.1
+        } else {
+            self.piece_sizes.0
+        }
+    }
+
+    /// Whether every missing block has been requested at least once.
+    pub fn in_endgame(&self) -> bool {
+        self.endgame
+    }
+
+    /// Picks the next block to request from a peer that has `has` and already has `in_flight`
+    /// outstanding.
+    ///
+    /// Pieces that are already in progress are finished first, so that complete pieces become
+    /// available quickly, otherwise a new piece is picked. In endgame, a block requested from
+    /// another peer is handed out again.
+    pub fn pick_block(&mut self, has: &Bitfield, in_flight: &[Block]) -> Option<Block> {
+        let free = self.partial.iter().find_map(|(&piece_i, blocks)| {
+            if !has.has(piece_i) {
+                return None;
+            }
+            let block_i = blocks.iter().position(|&b| b == BlockState::Free)?;
+            Some((piece_i, block_i))
+        });
+        let (piece_i, block_i) = match free {
+            Some(p) => p,
+            None => match self.pick(has) {
+                Some(piece_i) => {
+                    let nblocks = self.piece_size(piece_i).div_ceil(BLOCK_MAX) as usize;
+                    self.partial
+                        .insert(piece_i, vec![BlockState::Free; nblocks]);
+                    (piece_i, 0)
+                }
+                None => {
+                    if self.pieces.contains(&PieceState::Missing) {
+                        return None;
+                    }
+                    self.endgame = true;
+                    self.pick_duplicate(has, in_flight)?
+                }
+            },
+        };
+
+        let state = &mut self.partial.get_mut(&piece_i).unwrap()[block_i];
+        *state = match *state {
+            BlockState::Requested(n) => BlockState::Requested(n + 1),
+            _ => BlockState::Requested(1),
+        };
+        Some(self.block(piece_i, block_i))
+    }
+
+    /// Finds a block that is requested from other peers but not from this one.
+    fn pick_duplicate(&self, has: &Bitfield, in_flight: &[Block]) -> Option<(usize, usize)> {
+        self.partial.iter().find_map(|(&piece_i, blocks)| {
+            if !has.has(piece_i) {
+                return None;
+            }
+            let block_i = blocks.iter().enumerate().position(|(block_i, &b)| {
+                matches!(b, BlockState::Requested(_))
+                    && !in_flight.contains(&self.block(piece_i, block_i))
+            })?;
+            Some((piece_i, block_i))
+        })
+    }
+
+    fn block(&self, piece_i: usize, block_i: usize) -> Block {
+        let begin = block_i as u64 * BLOCK_MAX;
+        Block {
+            piece: piece_i,
+            begin: begin as u32,
+            length: BLOCK_MAX.min(self.piece_size(piece_i) - begin) as u32,
+        }
+    }
+
+    /// Picks a missing piece that `has` contains and marks it as in progress.
+    fn pick(&mut self, has: &Bitfield) -> Option<usize> {
+        let candidates =
+            (0..self.pieces.len()).filter(|&i| self.pieces[i] == PieceState::Missing && has.has(i));
+
+        let mut rng = thread_rng();
+        let piece_i = if self.done < RANDOM_FIRST_PIECES {
+            *candidates.collect::<Vec<_>>().choose(&mut rng)?
+        } else {
+            // Reservoir sampling over the pieces tied for the lowest availability.
+            let mut best = None;
+            let mut ties = 0;
+            for i in candidates {
+                match best {
+                    Some(b) if self.availability[i] > self.availability[b] => {}
+                    Some(b) if self.availability[i] == self.availability[b] => {
+                        ties += 1;
+                        if rng.gen_range(0..ties) == 0 {
+                            best = Some(i);
+                        }
+                    }
+                    _ => {
+                        best = Some(i);
+                        ties = 1;
+                    }
+                }
+            }
+            best?
+        };
+
+        self.pieces[piece_i] = PieceState::InProgress;
+        Some(piece_i)
+    }
+
+    /// Whether `block` is exactly one of the blocks pieces are split into, the only ones we ever
+    /// request.
+    pub fn is_block(&self, block: &Block) -> bool {
+        block.piece < self.pieces.len()
+            && (block.begin as u64).is_multiple_of(BLOCK_MAX)
+            && (block.begin as u64) < self.piece_size(block.piece)
+            && *block == self.block(block.piece, (block.begin as u64 / BLOCK_MAX) as usize)
+    }
+
+    fn block_state(&mut self, block: &Block) -> Option<&mut BlockState> {
+        if !self.is_block(block) {
+            return None;
+        }
+        let block_i = (block.begin as u64 / BLOCK_MAX) as usize;
+        self.partial.get_mut(&block.piece)?.get_mut(block_i)
+    }
+
+    /// Whether `block` belongs to a piece in progress and no peer delivered it yet.
+    pub fn is_wanted(&mut self, block: &Block) -> bool {
+        self.block_state(block)
+            .is_some_and(|state| matches!(state, BlockState::Free | BlockState::Requested(_)))
+    }
+
+    /// Claims a delivered block for writing. Returns `false` if it is not wanted, such as when
+    /// another peer's copy of it is already being written, and the data has to be dropped.
+    pub fn claim_block(&mut self, block: &Block) -> bool {
+        match self.block_state(block) {
+            Some(state @ (BlockState::Free | BlockState::Requested(_))) => {
+                *state = BlockState::Writing;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Frees a claimed block again after writing it failed.
+    pub fn release_block(&mut self, block: &Block) {
+        if let Some(state @ BlockState::Writing) = self.block_state(block) {
+            *state = BlockState::Free;
+        }
+    }
+
+    /// Drops one request for a block, after a choke, timeout or disconnect. The block is free
+    /// again once no peer has it requested anymore.
+    pub fn abort_block(&mut self, block: &Block) {
+        if let Some(state) = self.block_state(block) {
+            *state = match *state {
+                BlockState::Requested(n) if n > 1 => BlockState::Requested(n - 1),
+                BlockState::Requested(_) => BlockState::Free,
+                s => s,
+            };
+        }
+    }
+
+    /// Marks a claimed block as written. Returns `true` if this completed the piece.
+    pub fn block_received(&mut self, block: &Block) -> bool {
+        if let Some(state) = self.block_state(block) {
+            *state = BlockState::Received;
+        }
+        self.partial
+            .get(&block.piece)
+            .is_some_and(|blocks| blocks.iter().all(|&b| b == BlockState::Received))
+    }
+
+    /// Throws away a piece that failed its hash check so it is downloaded again.
+    pub fn piece_failed(&mut self, piece_i: usize) {
+        self.partial.remove(&piece_i);
+        if self.pieces[piece_i] == PieceState::InProgress {
+            self.pieces[piece_i] = PieceState::Missing;
+        }
+    }
+
+    /// Marks a piece as done, either verified or not wanted at all. Returns `false` if it
+    /// already was.
+    pub fn complete(&mut self, piece_i: usize) -> bool {
+        self.partial.remove(&piece_i);
+        if self.pieces[piece_i] == PieceState::Done {
+            return false;
+        }
+        self.pieces[piece_i] = PieceState::Done;
+        self.done += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::{Hashes, Keys};
+
+    /// A single-file torrent of `length` bytes in pieces of `plength`.
+    fn info(length: u64, plength: u64) -> Info {
+        Info {
+            name: "test".to_string(),
+            plength,
+            pieces: Hashes(vec![[0; 20]; length.div_ceil(plength) as usize]),
+            private: None,
+            keys: Keys::SingleFile { length },
+        }
+    }
+
+    #[test]
+    fn only_exact_blocks_are_wanted() {
+        // Two pieces of two blocks, the last block is 100 bytes.
+        let mut picker = PiecePicker::new(&info(2 * BLOCK_MAX + BLOCK_MAX + 100, 2 * BLOCK_MAX));
+        let has = Bitfield::from_bytes(vec![0xff], 2);
+        let mut in_flight = Vec::new();
+        while let Some(block) = picker.pick_block(&has, &in_flight) {
+            in_flight.push(block);
+        }
+
+        let exact = Block {
+            piece: 1,
+            begin: BLOCK_MAX as u32,
+            length: 100,
+        };
+        assert!(picker.is_block(&exact));
+        assert!(picker.is_wanted(&exact));
+        for bad in [
+            Block { begin: 1, ..exact },
+            Block {
+                length: 99,
+                ..exact
+            },
+            Block {
+                begin: 2 * BLOCK_MAX as u32,
+                ..exact
+            },
+            Block { piece: 2, ..exact },
+            Block {
+                piece: 0,
+                begin: 1,
+                length: 100,
+            },
+        ] {
+            assert!(!picker.is_block(&bad), "{bad:?}");
+            assert!(!picker.is_wanted(&bad), "{bad:?}");
+            assert!(!picker.block_received(&bad));
+        }
+        // The misaligned blocks did not take the place of block 0.
+        assert!(picker.is_wanted(&Block {
+            piece: 0,
+            begin: 0,
+            length: BLOCK_MAX as u32,
+        }));
+    }
+
+    #[test]
+    fn block_lifecycle() {
+        let mut picker = PiecePicker::new(&info(2 * BLOCK_MAX, 2 * BLOCK_MAX));
+        let has = Bitfield::from_bytes(vec![0x80], 1);
+        let first = picker.pick_block(&has, &[]).unwrap();
+        assert_eq!(picker.state(0), PieceState::InProgress);
+        assert_eq!(
+            first,
+            Block {
+                piece: 0,
+                begin: 0,
+                length: BLOCK_MAX as u32
+            }
+        );
+
+        // An aborted block is handed out again.
+        picker.abort_block(&first);
+        assert_eq!(picker.pick_block(&has, &[]), Some(first));
+        let second = picker.pick_block(&has, &[first]).unwrap();
+        assert_eq!(second.begin, BLOCK_MAX as u32);
+
+        assert!(!picker.block_received(&first));
+        assert!(!picker.is_wanted(&first));
+        assert!(picker.block_received(&second));
+
+        // A piece that fails its hash check starts over.
+        picker.piece_failed(0);
+        assert_eq!(picker.state(0), PieceState::Missing);
+        assert!(!picker.is_wanted(&first));
+        assert_eq!(picker.pick_block(&has, &[]), Some(first));
+
+        assert!(picker.complete(0));
+        assert!(!picker.complete(0));
+        assert_eq!(picker.remaining(), 0);
+        assert!(picker.have().is_full());
+        assert_eq!(picker.pick_block(&has, &[]), None);
+    }
+
+    #[test]
+    fn endgame_hands_out_blocks_once_per_peer() {
+        let mut picker = PiecePicker::new(&info(BLOCK_MAX, BLOCK_MAX));
+        let has = Bitfield::from_bytes(vec![0x80], 1);
+        let block = picker.pick_block(&has, &[]).unwrap();
+        assert!(!picker.in_endgame());
+
+        assert_eq!(picker.pick_block(&has, &[block]), None);
+        assert!(picker.in_endgame());
+        assert_eq!(picker.pick_block(&has, &[]), Some(block));
+
+        // Requested from two peers, one abort leaves it requested.
+        picker.abort_block(&block);
+        assert_eq!(picker.pick_block(&has, &[block]), None);
+        picker.abort_block(&block);
+        assert_eq!(picker.pick_block(&has, &[block]), Some(block));
+
+        assert!(picker.block_received(&block));
+        assert_eq!(picker.pick_block(&has, &[]), None);
+    }
+
+    #[test]
+    fn only_one_copy_of_a_block_is_written() {
+        let mut picker = PiecePicker::new(&info(BLOCK_MAX, BLOCK_MAX));
+        let has = Bitfield::from_bytes(vec![0x80], 1);
+        let block = picker.pick_block(&has, &[]).unwrap();
+        assert_eq!(picker.pick_block(&has, &[]), Some(block));
+
+        // The first copy to arrive is written, the other one dropped even once aborted.
+        assert!(picker.claim_block(&block));
+        assert!(!picker.is_wanted(&block));
+        assert!(!picker.claim_block(&block));
+        picker.abort_block(&block);
+        assert!(!picker.claim_block(&block));
+        assert_eq!(picker.pick_block(&has, &[]), None);
+
+        // A failed write frees the block for the next copy.
+        picker.release_block(&block);
+        assert!(picker.claim_block(&block));
+        assert!(picker.block_received(&block));
+        assert!(!picker.claim_block(&block));
+        picker.release_block(&block);
+        assert!(!picker.is_wanted(&block));
+    }
+
+    #[test]
+    fn no_endgame_while_pieces_are_missing() {
+        let mut picker = PiecePicker::new(&info(2 * BLOCK_MAX, BLOCK_MAX));
+        let has = Bitfield::from_bytes(vec![0x80], 2);
+        let block = picker.pick_block(&has, &[]).unwrap();
+        assert_eq!(picker.pick_block(&has, &[block]), None);
+        assert!(!picker.in_endgame());
+    }
+
+    #[test]
+    fn picks_the_rarest_piece_after_the_first_ones() {
+        let mut picker = PiecePicker::new(&info(8 * BLOCK_MAX, BLOCK_MAX));
+        for piece_i in 0..RANDOM_FIRST_PIECES {
+            picker.complete(piece_i);
+        }
+        let all = Bitfield::from_bytes(vec![0xff], 8);
+        picker.add_bitfield(&all);
+        picker.add_bitfield(&all);
+        picker.add_have(6);
+        let mut gone = Bitfield::new(8);
+        gone.set(4);
+        picker.remove_bitfield(&gone);
+        assert_eq!(
+            (4..8).map(|i| picker.availability(i)).collect::<Vec<_>>(),
+            [1, 2, 3, 2]
+        );
+        assert_eq!(picker.pick_block(&all, &[]).unwrap().piece, 4);
+    }
+}
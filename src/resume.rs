@@ -0,0 +1,204 @@
+use std::{net::SocketAddr, path::Path, time::UNIX_EPOCH};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::{bitfield::Bitfield, storage::split, torrent::FileSpan, tracker::peers::Peers};
+
+/// State saved next to a download so it can resume without hashing all the data again.
+///
+/// The bitfield is only used while every file still has the size it had when the state was saved,
+/// anything else falls back to a full recheck. Saved pieces are trusted only where no file they
+/// span was modified since, those in modified files are rechecked along with the pieces missing
+/// from the bitfield, which may have been written between saves.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FastResume {
+    #[serde(rename = "info-hash")]
+    info_hash: ByteBuf,
+    pieces: ByteBuf,
+    files: Vec<FileState>,
+    peers: ByteBuf,
+    #[serde(default)]
+    peers6: ByteBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct FileState {
+    length: u64,
+    /// Nanoseconds since the epoch, seconds are too coarse to notice a quick rewrite.
+    mtime: u64,
+}
+
+fn file_state(span: &FileSpan) -> Option<FileState> {
+    let metadata = std::fs::metadata(&span.path).ok()?;
+    let mtime = metadata
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_nanos() as u64;
+    Some(FileState {
+        length: metadata.len(),
+        mtime,
+    })
+}
+
+impl FastResume {
+    /// Captures the state of a download. Call it after flushing the storage, so the modification
+    /// times match the data on disk.
+    pub fn capture(
+        info_hash: [u8; 20],
+        have: &Bitfield,
+        layout: &[FileSpan],
+        peers: &[SocketAddr],
+    ) -> anyhow::Result<Self> {
+        let peers = Peers(peers.to_vec());
+        let files = layout
+            .iter()
+            .map(|span| file_state(span).with_context(|| format!("stat {}", span.path.display())))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(FastResume {
+            info_hash: ByteBuf::from(info_hash.to_vec()),
+            pieces: ByteBuf::from(have.as_bytes().to_vec()),
+            files,
+            peers: ByteBuf::from(peers.to_compact()),
+            peers6: ByteBuf::from(peers.to_compact6()),
+        })
+    }
+
+    /// Reads a fast-resume file, `None` if it does not exist.
+    pub fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("read fast-resume file"),
+        };
+        serde_bencode::from_bytes(&data)
+            .map(Some)
+            .context("parse fast-resume file")
+    }
+
+    /// Writes the file atomically, so a crash never leaves a truncated one behind.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let data = serde_bencode::to_bytes(self).context("encode fast-resume file")?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, data).context("write fast-resume file")?;
+        std::fs::rename(&tmp, path).context("replace fast-resume file")
+    }
+
+    /// The saved pieces that can be trusted without hashing, if the saved state still matches the
+    /// torrent and the files on disk, and whether any file was written to after the save.
+    pub fn validate(
+        &self,
+        info_hash: [u8; 20],
+        layout: &[FileSpan],
+        plength: u64,
+        npieces: usize,
+    ) -> Option<(Bitfield, bool)> {
+        if self.info_hash.as_slice() != info_hash || self.files.len() != layout.len() {
+            return None;
+        }
+        let mut modified = Vec::with_capacity(layout.len());
+        for (saved, span) in self.files.iter().zip(layout) {
+            let state = file_state(span)?;
+            if state.length != saved.length {
+                return None;
+            }
+            modified.push(state.mtime != saved.mtime);
+        }
+
+        let mut have = Bitfield::from_bytes(self.pieces.to_vec(), npieces);
+        let length: u64 = layout.iter().map(|span| span.length).sum();
+        for piece_i in have.clone().iter() {
+            let offset = piece_i as u64 * plength;
+            let len = plength.min(length.saturating_sub(offset)) as usize;
+            if split(layout.iter(), offset, len).any(|(i, _, _)| modified[i]) {
+                have.unset(piece_i);
+            }
+        }
+        Some((have, modified.contains(&true)))
+    }
+
+    /// Peers we were connected to when the state was saved.
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        let peers = Peers::from_compact(&self.peers).map_or_else(Vec::new, |p| p.0);
+        let peers6 = Peers::from_compact6(&self.peers6).map_or_else(Vec::new, |p| p.0);
+        peers.into_iter().chain(peers6).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two files of 100 and 50 bytes, in pieces of 40 bytes.
+    fn layout(dir: &Path) -> Vec<FileSpan> {
+        let mut offset = 0;
+        [("a", 100), ("b", 50)]
+            .into_iter()
+            .map(|(name, length)| {
+                let path = dir.join(name);
+                std::fs::write(&path, vec![0; length as usize]).unwrap();
+                let span = FileSpan {
+                    path,
+                    offset,
+                    length,
+                };
+                offset += length;
+                span
+            })
+            .collect()
+    }
+
+    #[test]
+    fn validate_trusts_saved_pieces_of_unmodified_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = layout(dir.path());
+        let mut have = Bitfield::new(4);
+        for piece_i in [0, 2, 3] {
+            have.set(piece_i);
+        }
+        let resume = FastResume::capture([1; 20], &have, &layout, &[]).unwrap();
+
+        assert_eq!(
+            resume.validate([1; 20], &layout, 40, 4),
+            Some((have.clone(), false))
+        );
+        assert_eq!(resume.validate([2; 20], &layout, 40, 4), None);
+
+        // Piece 2 spans both files, piece 3 only the second one.
+        let file = std::fs::File::options()
+            .write(true)
+            .open(&layout[1].path)
+            .unwrap();
+        file.set_modified(UNIX_EPOCH).unwrap();
+        let mut trusted = Bitfield::new(4);
+        trusted.set(0);
+        assert_eq!(
+            resume.validate([1; 20], &layout, 40, 4),
+            Some((trusted, true))
+        );
+
+        file.set_len(49).unwrap();
+        assert_eq!(resume.validate([1; 20], &layout, 40, 4), None);
+    }
+
+    #[test]
+    fn round_trips_through_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = layout(dir.path());
+        let peers: Vec<SocketAddr> = vec!["1.2.3.4:5".parse().unwrap(), "[::1]:6".parse().unwrap()];
+        let path = dir.path().join("state");
+        FastResume::capture([1; 20], &Bitfield::new(10), &layout, &peers)
+            .unwrap()
+            .save(&path)
+            .unwrap();
+
+        let resume = FastResume::load(&path).unwrap().unwrap();
+        assert_eq!(resume.peers(), peers);
+        assert!(FastResume::load(&dir.path().join("missing"))
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,457 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::{bail, Context};
+#[cfg(feature = "mmap")]
+use memmap2::MmapMut;
+use sha1::{Digest, Sha1};
+
+use crate::torrent::{FileSpan, Info};
+
+/// Where the download engine reads and writes the torrent data.
+///
+/// Offsets are given per piece, backends map them onto whatever they store the contiguous piece
+/// space in. Implement this to route pieces into your own store.
+pub trait Storage: Send + Sync {
+    /// Reads `length` bytes of piece `piece_i` starting at `begin`.
+    fn read_block(&self, piece_i: usize, begin: u32, length: u32) -> anyhow::Result<Vec<u8>>;
+
+    /// Writes a block received for piece `piece_i` at `begin`.
+    fn write_block(&self, piece_i: usize, begin: u32, data: &[u8]) -> anyhow::Result<()>;
+
+    /// Checks the stored data of piece `piece_i` against its hash from the info dictionary.
+    fn verify_piece(&self, piece_i: usize) -> anyhow::Result<bool>;
+
+    /// Makes sure everything written so far is persisted.
+    fn flush(&self) -> anyhow::Result<()>;
+}
+
+/// Start offset and size of every piece, shared by the backends.
+#[derive(Debug, Clone)]
+struct Pieces {
+    plength: u64,
+    length: u64,
+    hashes: Vec<[u8; 20]>,
+}
+
+impl Pieces {
+    fn new(info: &Info) -> Self {
+        Pieces {
+            plength: info.plength,
+            length: info.length(),
+            hashes: info.pieces.0.clone(),
+        }
+    }
+
+    /// Absolute offset of a block in the piece space, checked against the torrent size.
+    fn offset(&self, piece_i: usize, begin: u32, length: usize) -> anyhow::Result<u64> {
+        let offset = piece_i as u64 * self.plength + begin as u64;
+        if piece_i >= self.hashes.len()
+            || begin as u64 + length as u64 > self.plength
+            || offset + length as u64 > self.length
+        {
+            bail!("block {piece_i}:{begin}+{length} is outside of the torrent");
+        }
+        Ok(offset)
+    }
+
+    fn piece_size(&self, piece_i: usize) -> u32 {
+        let start = piece_i as u64 * self.plength;
+        (self.length - start).min(self.plength) as u32
+    }
+
+    fn verify(&self, storage: &dyn Storage, piece_i: usize) -> anyhow::Result<bool> {
+        let data = storage.read_block(piece_i, 0, self.piece_size(piece_i))?;
+        let mut hasher = Sha1::new();
+        hasher.update(&data);
+        let hash: [u8; 20] = hasher.finalize().into();
+        Ok(hash == self.hashes[piece_i])
+    }
+}
+
+/// Parts of the byte range `offset..offset + len` that fall into each file, as
+/// `(file index, offset in file, range in the buffer)`.
+pub(crate) fn split<'a>(
+    spans: impl Iterator<Item = &'a FileSpan> + 'a,
+    offset: u64,
+    len: usize,
+) -> impl Iterator<Item = (usize, u64, std::ops::Range<usize>)> + 'a {
+    let end = offset + len as u64;
+    spans.enumerate().filter_map(move |(i, span)| {
+        let start = offset.max(span.offset);
+        let stop = end.min(span.offset + span.length);
+        (start < stop).then(|| {
+            (
+                i,
+                start - span.offset,
+                (start - offset) as usize..(stop - offset) as usize,
+            )
+        })
+    })
+}
+
+/// Reads the byte range of the piece space starting at `offset` into `buf` from the files of
+/// `layout`, opening them read-only into `files` on first use.
+pub(crate) fn read_range(
+    layout: &[FileSpan],
+    files: &mut [Option<File>],
+    offset: u64,
+    buf: &mut [u8],
+) -> std::io::Result<()> {
+    for (i, file_offset, range) in split(layout.iter(), offset, buf.len()) {
+        let file = match &mut files[i] {
+            Some(file) => file,
+            slot => slot.insert(File::open(&layout[i].path)?),
+        };
+        file.seek(SeekFrom::Start(file_offset))?;
+        file.read_exact(&mut buf[range])?;
+    }
+    Ok(())
+}
+
+/// Runs `f` for every piece index on one thread per core and collects the results in order.
+/// Every thread gets its own cache of open files for [`read_range`].
+pub(crate) fn par_pieces<T, F>(npieces: usize, nfiles: usize, f: F) -> Vec<T>
+where
+    T: Send,
+    F: Fn(&mut [Option<File>], usize) -> T + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let workers = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut results: Vec<Option<T>> = (0..npieces).map(|_| None).collect();
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut files: Vec<Option<File>> = (0..nfiles).map(|_| None).collect();
+                    let mut done = Vec::new();
+                    loop {
+                        let piece_i = next.fetch_add(1, Ordering::Relaxed);
+                        if piece_i >= npieces {
+                            break done;
+                        }
+                        done.push((piece_i, f(&mut files, piece_i)));
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            for (piece_i, result) in handle.join().expect("piece worker panicked") {
+                results[piece_i] = Some(result);
+            }
+        }
+    });
+    results
+        .into_iter()
+        .map(|r| r.expect("every piece is processed"))
+        .collect()
+}
+
+/// Opens every file of `layout`, creating it and its parent directories if needed, sized to its
+/// final length so pieces can land in any order. Existing data is kept.
+fn open_files(layout: &[FileSpan]) -> anyhow::Result<Vec<File>> {
+    let mut files = Vec::with_capacity(layout.len());
+    for span in layout {
+        if let Some(parent) = span.path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("create directory {}", parent.display()))?;
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&span.path)
+            .with_context(|| format!("open {}", span.path.display()))?;
+        if file.metadata()?.len() != span.length {
+            file.set_len(span.length)
+                .with_context(|| format!("allocate {}", span.path.display()))?;
+        }
+        files.push(file);
+    }
+    Ok(files)
+}
+
+/// Stores the torrent in files on disk, sparse where the filesystem allows it.
+pub struct FileStorage {
+    pieces: Pieces,
+    files: Vec<(FileSpan, Mutex<File>)>,
+}
+
+impl FileStorage {
+    /// Opens the files of `layout`. The layout does not have to cover the whole torrent, writes
+    /// outside of it are dropped and reads return zeroes.
+    pub fn create(info: &Info, layout: Vec<FileSpan>) -> anyhow::Result<Self> {
+        let files = open_files(&layout)?;
+        Ok(FileStorage {
+            pieces: Pieces::new(info),
+            files: layout
+                .into_iter()
+                .zip(files.into_iter().map(Mutex::new))
+                .collect(),
+        })
+    }
+}
+
+impl Storage for FileStorage {
+    fn read_block(&self, piece_i: usize, begin: u32, length: u32) -> anyhow::Result<Vec<u8>> {
+        let offset = self.pieces.offset(piece_i, begin, length as usize)?;
+        let mut data = vec![0; length as usize];
+        for (i, file_offset, range) in split(self.files.iter().map(|f| &f.0), offset, data.len()) {
+            let (span, file) = &self.files[i];
+            let mut file = file.lock().unwrap();
+            file.seek(SeekFrom::Start(file_offset))?;
+            file.read_exact(&mut data[range])
+                .with_context(|| format!("read {}", span.path.display()))?;
+        }
+        Ok(data)
+    }
+
+    fn write_block(&self, piece_i: usize, begin: u32, data: &[u8]) -> anyhow::Result<()> {
+        let offset = self.pieces.offset(piece_i, begin, data.len())?;
+        for (i, file_offset, range) in split(self.files.iter().map(|f| &f.0), offset, data.len()) {
+            let (span, file) = &self.files[i];
+            let mut file = file.lock().unwrap();
+            file.seek(SeekFrom::Start(file_offset))?;
+            file.write_all(&data[range])
+                .with_context(|| format!("write {}", span.path.display()))?;
+        }
+        Ok(())
+    }
+
+    fn verify_piece(&self, piece_i: usize) -> anyhow::Result<bool> {
+        self.pieces.verify(self, piece_i)
+    }
+
+    fn flush(&self) -> anyhow::Result<()> {
+        for (span, file) in &self.files {
+            file.lock()
+                .unwrap()
+                .sync_all()
+                .with_context(|| format!("sync {}", span.path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps the whole torrent in memory, mostly useful for tests and small torrents.
+pub struct MemoryStorage {
+    pieces: Pieces,
+    data: Mutex<Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new(info: &Info) -> Self {
+        let pieces = Pieces::new(info);
+        MemoryStorage {
+            data: Mutex::new(vec![0; pieces.length as usize]),
+            pieces,
+        }
+    }
+
+    /// The contiguous torrent data.
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data.into_inner().unwrap()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read_block(&self, piece_i: usize, begin: u32, length: u32) -> anyhow::Result<Vec<u8>> {
+        let offset = self.pieces.offset(piece_i, begin, length as usize)? as usize;
+        Ok(self.data.lock().unwrap()[offset..offset + length as usize].to_vec())
+    }
+
+    fn write_block(&self, piece_i: usize, begin: u32, data: &[u8]) -> anyhow::Result<()> {
+        let offset = self.pieces.offset(piece_i, begin, data.len())? as usize;
+        self.data.lock().unwrap()[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn verify_piece(&self, piece_i: usize) -> anyhow::Result<bool> {
+        self.pieces.verify(self, piece_i)
+    }
+
+    fn flush(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Stores the torrent in memory-mapped files, letting the OS page cache do the buffering.
+///
+/// Only built with the `mmap` feature.
+#[cfg(feature = "mmap")]
+pub struct MmapStorage {
+    pieces: Pieces,
+    /// Empty files cannot be mapped, they have no map but still take part in the layout.
+    files: Vec<(FileSpan, Option<Mutex<MmapMut>>)>,
+}
+
+#[cfg(feature = "mmap")]
+impl MmapStorage {
+    /// Opens and maps the files of `layout`, see [`FileStorage::create`].
+    pub fn create(info: &Info, layout: Vec<FileSpan>) -> anyhow::Result<Self> {
+        let files = open_files(&layout)?;
+        let mut maps = Vec::with_capacity(files.len());
+        for (span, file) in layout.into_iter().zip(files) {
+            let map = if span.length == 0 {
+                None
+            } else {
+                // Safety: the map is only accessed through the mutex, other processes modifying
+                // the file while we download it is outside of what we can guard against.
+                let map = unsafe { MmapMut::map_mut(&file) }
+                    .with_context(|| format!("map {}", span.path.display()))?;
+                Some(Mutex::new(map))
+            };
+            maps.push((span, map));
+        }
+        Ok(MmapStorage {
+            pieces: Pieces::new(info),
+            files: maps,
+        })
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl Storage for MmapStorage {
+    fn read_block(&self, piece_i: usize, begin: u32, length: u32) -> anyhow::Result<Vec<u8>> {
+        let offset = self.pieces.offset(piece_i, begin, length as usize)?;
+        let mut data = vec![0; length as usize];
+        for (i, file_offset, range) in split(self.files.iter().map(|f| &f.0), offset, data.len()) {
+            if let Some(map) = &self.files[i].1 {
+                let start = file_offset as usize;
+                data[range.clone()]
+                    .copy_from_slice(&map.lock().unwrap()[start..start + range.len()]);
+            }
+        }
+        Ok(data)
+    }
+
+    fn write_block(&self, piece_i: usize, begin: u32, data: &[u8]) -> anyhow::Result<()> {
+        let offset = self.pieces.offset(piece_i, begin, data.len())?;
+        for (i, file_offset, range) in split(self.files.iter().map(|f| &f.0), offset, data.len()) {
+            if let Some(map) = &self.files[i].1 {
+                let start = file_offset as usize;
+                map.lock().unwrap()[start..start + range.len()].copy_from_slice(&data[range]);
+            }
+        }
+        Ok(())
+    }
+
+    fn verify_piece(&self, piece_i: usize) -> anyhow::Result<bool> {
+        self.pieces.verify(self, piece_i)
+    }
+
+    fn flush(&self) -> anyhow::Result<()> {
+        for (span, map) in &self.files {
+            if let Some(map) = map {
+                map.lock()
+                    .unwrap()
+                    .flush()
+                    .with_context(|| format!("flush {}", span.path.display()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::{File as TorrentFile, Hashes, Keys};
+
+    /// A multi-file torrent of `files` with their content, in pieces of `plength`.
+    fn info(files: &[&[u8]], plength: u64) -> Info {
+        let data = files.concat();
+        Info {
+            name: "test".to_string(),
+            plength,
+            pieces: Hashes(
+                data.chunks(plength as usize)
+                    .map(|chunk| Sha1::digest(chunk).into())
+                    .collect(),
+            ),
+            private: None,
+            keys: Keys::MultiFile {
+                files: files
+                    .iter()
+                    .enumerate()
+                    .map(|(i, f)| TorrentFile {
+                        length: f.len() as u64,
+                        path: vec![i.to_string()],
+                    })
+                    .collect(),
+            },
+        }
+    }
+
+    #[test]
+    fn offset_is_checked_against_the_piece_and_torrent() {
+        // Pieces of 4 bytes, the last one holds 2.
+        let pieces = Pieces::new(&info(&[b"0123456789"], 4));
+        assert_eq!(pieces.offset(0, 0, 4).unwrap(), 0);
+        assert_eq!(pieces.offset(1, 1, 3).unwrap(), 5);
+        assert_eq!(pieces.offset(2, 0, 2).unwrap(), 8);
+        assert_eq!(pieces.piece_size(2), 2);
+
+        assert!(pieces.offset(1, 2, 3).is_err());
+        assert!(pieces.offset(2, 0, 3).is_err());
+        assert!(pieces.offset(3, 0, 1).is_err());
+        assert!(pieces.offset(0, u32::MAX, 1).is_err());
+    }
+
+    #[test]
+    fn split_spreads_a_range_over_files() {
+        let spans: Vec<_> = [3, 0, 2, 5]
+            .into_iter()
+            .scan(0, |offset, length| {
+                let span = FileSpan {
+                    path: std::path::PathBuf::new(),
+                    offset: *offset,
+                    length,
+                };
+                *offset += length;
+                Some(span)
+            })
+            .collect();
+        assert_eq!(
+            split(spans.iter(), 1, 6).collect::<Vec<_>>(),
+            [(0, 1, 0..2), (2, 0, 2..4), (3, 0, 4..6)]
+        );
+        assert_eq!(
+            split(spans.iter(), 6, 2).collect::<Vec<_>>(),
+            [(3, 1, 0..2)]
+        );
+        assert_eq!(split(spans.iter(), 10, 1).count(), 0);
+    }
+
+    #[test]
+    fn file_storage_writes_blocks_across_files() {
+        let files: [&[u8]; 3] = [b"abc", b"", b"defghij"];
+        let info = info(&files, 4);
+        let dir = tempfile::tempdir().unwrap();
+        let layout = info.layout(dir.path()).unwrap();
+        let storage = FileStorage::create(&info, layout).unwrap();
+
+        let data = files.concat();
+        for (piece_i, piece) in data.chunks(4).enumerate() {
+            assert!(!storage.verify_piece(piece_i).unwrap());
+            storage.write_block(piece_i, 0, &piece[..1]).unwrap();
+            storage.write_block(piece_i, 1, &piece[1..]).unwrap();
+            assert!(storage.verify_piece(piece_i).unwrap());
+        }
+        assert!(storage.write_block(2, 0, b"xyz").is_err());
+        storage.flush().unwrap();
+
+        assert_eq!(storage.read_block(0, 2, 2).unwrap(), b"cd");
+        for (i, content) in files.iter().enumerate() {
+            let path = dir.path().join(i.to_string());
+            assert_eq!(std::fs::read(path).unwrap(), *content);
+        }
+    }
+}
@@ -0,0 +1,1038 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, watch, Notify},
+    task::JoinSet,
+    time::timeout,
+};
+use tokio_util::codec::Framed;
+
+use crate::{
+    bitfield::Bitfield,
+    choker::{Candidate, Choker, CHOKE_INTERVAL},
+    peer::{FrameLimits, Handshake, MessageCodec, PeerMessage, BLOCK_MAX},
+    picker::{Block, PiecePicker},
+    storage::Storage,
+    torrent::Torrent,
+};
+
+/// Number of peers we keep connections open to at the same time.
+const MAX_PEERS: usize = 30;
+/// How long we give a peer to accept our connection and answer the handshake.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long we wait for any of our outstanding requests before giving up on the peer.
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a peer can stay silent while we have nothing to request from it.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+/// How often we send a keepalive, so peers with the same timeout do not drop us.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(90);
+
+/// Tunables of the per-peer request pipeline.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Outstanding requests we keep with a peer, however slow it is.
+    pub min_requests: usize,
+    /// Upper bound on outstanding requests with a peer.
+    pub max_requests: usize,
+    /// The pipeline holds enough requests to keep a peer busy for this long at its measured
+    /// download rate, like libtorrent's `request_queue_time`.
+    pub request_queue_time: Duration,
+    /// Peers we upload to at the same time, besides the optimistic unchoke.
+    pub unchoke_slots: usize,
+    /// Largest messages we exchange with peers, `None` sizes them for the torrent.
+    pub frame_limits: Option<FrameLimits>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            min_requests: 4,
+            max_requests: 250,
+            request_queue_time: Duration::from_secs(3),
+            unchoke_slots: 4,
+            frame_limits: None,
+        }
+    }
+}
+
+struct State {
+    picker: PiecePicker,
+    /// Pieces that are verified in the storage, the only ones we serve to peers. Unlike the
+    /// picker, pieces skipped by `download_piece` are not in it.
+    verified: Bitfield,
+    /// Peers handed to the swarm that it has not connected to yet.
+    pending: VecDeque<SocketAddr>,
+    /// Connections accepted by `listen` whose handshake is done.
+    incoming: Vec<(SocketAddr, TcpStream)>,
+    /// Every open connection, by `PeerConn::id`.
+    peers: HashMap<u64, PeerStatus>,
+}
+
+/// What the choker needs to know about a connection.
+struct PeerStatus {
+    interested: bool,
+    /// Bytes received from the peer since the last choker round.
+    downloaded: u64,
+    /// Bytes sent to the peer since the last choker round.
+    uploaded: u64,
+    /// Whether the peer should be unchoked, the connection sends `Choke`/`Unchoke` when it
+    /// changes.
+    unchoke: watch::Sender<bool>,
+}
+
+/// Transfer totals of a swarm, as reported to trackers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    /// Bytes of the torrent we do not have verified yet.
+    pub left: u64,
+}
+
+struct Shared {
+    torrent: Torrent,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    config: Config,
+    storage: Arc<dyn Storage>,
+    state: Mutex<State>,
+    done: watch::Sender<bool>,
+    /// Blocks that arrived while in endgame, so other peers can cancel their duplicate requests.
+    received: broadcast::Sender<Block>,
+    /// Pieces we completed, every connection tells its peer with a `Have`.
+    have: broadcast::Sender<usize>,
+    /// Wakes up the connection loop when peers are added to `State::pending` or
+    /// `State::incoming`.
+    new_peers: Notify,
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    next_conn_id: AtomicU64,
+}
+
+impl Shared {
+    fn pick_block(&self, has: &Bitfield, in_flight: &[Block]) -> Option<Block> {
+        self.state.lock().unwrap().picker.pick_block(has, in_flight)
+    }
+
+    /// Gives requested blocks back so another peer can pick them up.
+    fn abort(&self, blocks: &[Block]) {
+        let mut state = self.state.lock().unwrap();
+        for block in blocks {
+            state.picker.abort_block(block);
+        }
+    }
+
+    /// Claims a delivered block so only this copy of it is written, see
+    /// [`PiecePicker::claim_block`].
+    fn claim(&self, block: &Block) -> bool {
+        self.state.lock().unwrap().picker.claim_block(block)
+    }
+
+    fn release(&self, block: &Block) {
+        self.state.lock().unwrap().picker.release_block(block);
+    }
+
+    /// Records a block that was written to storage. Returns `true` once all blocks of its
+    /// piece are in.
+    fn block_received(&self, block: &Block) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let complete = state.picker.block_received(block);
+        if state.picker.in_endgame() {
+            // Nobody listening is fine, it only means no other peer is connected.
+            let _ = self.received.send(*block);
+        }
+        complete
+    }
+
+    /// Marks a piece that is verified and written to storage as done.
+    fn complete(&self, piece_i: usize) {
+        let mut state = self.state.lock().unwrap();
+        if !state.picker.complete(piece_i) {
+            return;
+        }
+        state.verified.set(piece_i);
+        let _ = self.have.send(piece_i);
+        if state.picker.remaining() == 0 {
+            self.done.send_replace(true);
+        }
+    }
+
+    /// Unchokes the peers the choker picks out of the open connections, and starts measuring
+    /// their rates over again.
+    fn rechoke(&self, choker: &mut Choker) {
+        let mut state = self.state.lock().unwrap();
+        let seeding = state.picker.remaining() == 0;
+        let secs = CHOKE_INTERVAL.as_secs().max(1);
+        let candidates: Vec<Candidate> = state
+            .peers
+            .iter_mut()
+            .map(|(&id, status)| {
+                let bytes = if seeding {
+                    status.uploaded
+                } else {
+                    status.downloaded
+                };
+                (status.downloaded, status.uploaded) = (0, 0);
+                Candidate {
+                    id,
+                    interested: status.interested,
+                    rate: bytes / secs,
+                }
+            })
+            .collect();
+
+        let unchoked = choker.round(&candidates);
+        for (id, status) in &state.peers {
+            status.unchoke.send_if_modified(|unchoke| {
+                let changed = *unchoke != unchoked.contains(id);
+                *unchoke = unchoked.contains(id);
+                changed
+            });
+        }
+    }
+
+    fn has_piece(&self, piece_i: usize) -> bool {
+        self.state.lock().unwrap().verified.has(piece_i)
+    }
+
+    fn piece_failed(&self, piece_i: usize) {
+        self.state.lock().unwrap().picker.piece_failed(piece_i);
+    }
+
+    fn remaining(&self) -> usize {
+        self.state.lock().unwrap().picker.remaining()
+    }
+}
+
+/// Downloads a torrent from many peers at once.
+///
+/// Every connection runs in its own task and asks the swarm for the next blocks it can serve, so
+/// a slow or dead peer only holds up the blocks it was asked for, which go back to the pool when
+/// the connection fails. Blocks go to the storage as they arrive and every piece is verified
+/// there once complete.
+///
+/// Peers get the pieces we verified in return, from the same connections and from the ones
+/// `listen` accepts.
+pub struct Swarm {
+    shared: Arc<Shared>,
+}
+
+impl Swarm {
+    pub fn new(torrent: Torrent, peer_id: [u8; 20], storage: Arc<dyn Storage>) -> Self {
+        Self::with_config(torrent, peer_id, storage, Config::default())
+    }
+
+    pub fn with_config(
+        torrent: Torrent,
+        peer_id: [u8; 20],
+        storage: Arc<dyn Storage>,
+        config: Config,
+    ) -> Self {
+        let npieces = torrent.info().pieces.0.len();
+        let (done, _) = watch::channel(npieces == 0);
+        let (received, _) = broadcast::channel(256);
+        let (have, _) = broadcast::channel(256);
+        Swarm {
+            shared: Arc::new(Shared {
+                info_hash: torrent.info_hash(),
+                state: Mutex::new(State {
+                    picker: PiecePicker::new(torrent.info()),
+                    verified: Bitfield::new(npieces),
+                    pending: VecDeque::new(),
+                    incoming: Vec::new(),
+                    peers: HashMap::new(),
+                }),
+                torrent,
+                peer_id,
+                config,
+                storage,
+                done,
+                received,
+                have,
+                new_peers: Notify::new(),
+                uploaded: AtomicU64::new(0),
+                downloaded: AtomicU64::new(0),
+                next_conn_id: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Marks the pieces in `have` as done without checking them, e.g. from a fast-resume file.
+    pub fn resume(&self, have: &Bitfield) {
+        let mut state = self.shared.state.lock().unwrap();
+        for piece_i in have.iter() {
+            state.picker.complete(piece_i);
+            state.verified.set(piece_i);
+        }
+        if state.picker.remaining() == 0 {
+            self.shared.done.send_replace(true);
+        }
+    }
+
+    /// Hashes the data already in the storage and marks the pieces that check out as done, along
+    /// with the pieces in `known` which are trusted without hashing. Returns how many pieces were
+    /// verified.
+    pub async fn recheck(&self, known: &Bitfield) -> anyhow::Result<usize> {
+        let npieces = self.shared.torrent.info().pieces.0.len();
+        let parallelism = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let mut checks = futures::stream::iter((0..npieces).filter(|&i| !known.has(i)))
+            .map(|piece_i| {
+                let storage = Arc::clone(&self.shared.storage);
+                async move {
+                    let verified =
+                        tokio::task::spawn_blocking(move || storage.verify_piece(piece_i)).await;
+                    (piece_i, verified)
+                }
+            })
+            .buffer_unordered(parallelism);
+
+        let mut have = known.clone();
+        while let Some((piece_i, verified)) = checks.next().await {
+            if verified?? {
+                have.set(piece_i);
+            }
+        }
+        self.resume(&have);
+        Ok(have.count())
+    }
+
+    /// The pieces that are downloaded and verified so far.
+    pub fn have(&self) -> Bitfield {
+        self.shared.state.lock().unwrap().verified.clone()
+    }
+
+    /// Bytes transferred so far and bytes still to download.
+    pub fn stats(&self) -> Stats {
+        let info = self.shared.torrent.info();
+        let have = self.have();
+        let verified: u64 = have.iter().map(|piece_i| info.piece_size(piece_i)).sum();
+        Stats {
+            uploaded: self.shared.uploaded.load(Ordering::Relaxed),
+            downloaded: self.shared.downloaded.load(Ordering::Relaxed),
+            left: info.length() - verified,
+        }
+    }
+
+    /// A receiver that turns `true` once every piece is downloaded and verified.
+    pub fn done(&self) -> watch::Receiver<bool> {
+        self.shared.done.subscribe()
+    }
+
+    /// Hands more peers to a running download, e.g. from a re-announce. Peers we are already
+    /// connected to are skipped.
+    pub fn add_peers(&self, peers: impl IntoIterator<Item = SocketAddr>) {
+        self.shared.state.lock().unwrap().pending.extend(peers);
+        self.shared.new_peers.notify_one();
+    }
+
+    /// Makes sure everything downloaded so far is persisted.
+    pub fn flush(&self) -> anyhow::Result<()> {
+        self.shared.storage.flush()
+    }
+
+    /// Downloads every piece from `peers` into the storage.
+    pub async fn download(&self, peers: Vec<SocketAddr>) -> anyhow::Result<()> {
+        self.run(peers, false).await?;
+        self.shared.storage.flush()
+    }
+
+    /// Downloads what is missing from `peers`, and keeps uploading to them and to the peers that
+    /// connect to us once the download is complete. Only returns on errors, drop the future to
+    /// stop seeding.
+    pub async fn seed(&self, peers: Vec<SocketAddr>) -> anyhow::Result<()> {
+        self.run(peers, true).await
+    }
+
+    /// Downloads only piece `piece_i` from `peers` into the storage.
+    pub async fn download_piece(
+        &self,
+        peers: Vec<SocketAddr>,
+        piece_i: usize,
+    ) -> anyhow::Result<()> {
+        {
+            let npieces = self.shared.torrent.info().pieces.0.len();
+            if piece_i >= npieces {
+                bail!("torrent has no piece {piece_i}");
+            }
+            let mut state = self.shared.state.lock().unwrap();
+            for i in (0..npieces).filter(|&i| i != piece_i) {
+                state.picker.complete(i);
+            }
+        }
+        self.run(peers, false).await?;
+        self.shared.storage.flush()
+    }
+
+    /// Keeps up to `MAX_PEERS` connections open, to `peers`, peers added later and peers that
+    /// connect to us. Without `seed` it returns once every piece is in.
+    async fn run(&self, peers: Vec<SocketAddr>, seed: bool) -> anyhow::Result<()> {
+        let mut done = self.shared.done.subscribe();
+        if !seed && *done.borrow() {
+            return Ok(());
+        }
+        self.shared.state.lock().unwrap().pending.extend(peers);
+        let mut connected = HashSet::new();
+        let mut tasks = JoinSet::new();
+        let mut choker = Choker::new(self.shared.config.unchoke_slots);
+        let mut rechoke = tokio::time::interval(CHOKE_INTERVAL);
+
+        loop {
+            let incoming = std::mem::take(&mut self.shared.state.lock().unwrap().incoming);
+            for (addr, stream) in incoming {
+                // Dropping the stream turns the peer away when we are full.
+                if tasks.len() < MAX_PEERS && connected.insert(addr) {
+                    self.spawn_peer(&mut tasks, addr, Some(stream));
+                }
+            }
+            while tasks.len() < MAX_PEERS {
+                let Some(addr) = self.shared.state.lock().unwrap().pending.pop_front() else {
+                    break;
+                };
+                if connected.insert(addr) {
+                    self.spawn_peer(&mut tasks, addr, None);
+                }
+            }
+            if tasks.is_empty() && !seed {
+                break;
+            }
+
+            tokio::select! {
+                _ = done.wait_for(|done| *done), if !seed => break,
+                Some(res) = tasks.join_next() => {
+                    match res {
+                        Ok((addr, res)) => {
+                            connected.remove(&addr);
+                            if let Err(e) = res {
+                                eprintln!("peer {addr}: {e:#}");
+                            }
+                        }
+                        Err(e) => eprintln!("peer task failed: {e}"),
+                    }
+                }
+                _ = self.shared.new_peers.notified() => {}
+                _ = rechoke.tick() => self.shared.rechoke(&mut choker),
+            }
+        }
+        // Dropping the set aborts the connections that are still open.
+        drop(tasks);
+
+        let remaining = self.shared.remaining();
+        if remaining != 0 {
+            bail!("all peers disconnected with {remaining} pieces missing");
+        }
+        Ok(())
+    }
+
+    fn spawn_peer(
+        &self,
+        tasks: &mut JoinSet<(SocketAddr, anyhow::Result<()>)>,
+        addr: SocketAddr,
+        stream: Option<TcpStream>,
+    ) {
+        let shared = Arc::clone(&self.shared);
+        tasks.spawn(async move { (addr, run_peer(shared, addr, stream).await) });
+    }
+}
+
+/// Accepts connections from peers and hands each to the swarm of the torrent it asks for in its
+/// handshake. The swarms take them on while they are downloading or seeding.
+pub async fn listen(listener: TcpListener, swarms: &[&Swarm]) -> anyhow::Result<()> {
+    let swarms: Arc<HashMap<[u8; 20], Arc<Shared>>> = Arc::new(
+        swarms
+            .iter()
+            .map(|swarm| (swarm.shared.info_hash, Arc::clone(&swarm.shared)))
+            .collect(),
+    );
+    loop {
+        let (stream, addr) = listener.accept().await.context("accept peer")?;
+        let swarms = Arc::clone(&swarms);
+        tokio::spawn(async move {
+            match timeout(CONNECT_TIMEOUT, accept(stream, &swarms)).await {
+                Ok(Ok((shared, stream))) => {
+                    shared.state.lock().unwrap().incoming.push((addr, stream));
+                    shared.new_peers.notify_one();
+                }
+                Ok(Err(e)) => eprintln!("incoming peer {addr}: {e:#}"),
+                Err(_) => eprintln!("incoming peer {addr}: handshake timed out"),
+            }
+        });
+    }
+}
+
+/// Answers the handshake of a peer that connected to us, if it is for one of `swarms`.
+async fn accept(
+    mut stream: TcpStream,
+    swarms: &HashMap<[u8; 20], Arc<Shared>>,
+) -> anyhow::Result<(Arc<Shared>, TcpStream)> {
+    let mut handshake_b = [0; Handshake::LEN];
+    stream.read_exact(&mut handshake_b).await?;
+    let handshake = Handshake::from_bytes(&handshake_b);
+    if &handshake.msg != b"BitTorrent protocol" {
+        bail!("peer sent an invalid handshake");
+    }
+    let shared = swarms
+        .get(&handshake.info_hash)
+        .context("peer asked for a torrent we do not have")?;
+
+    let handshake = Handshake::new(shared.info_hash, shared.peer_id);
+    stream.write_all(&handshake.to_bytes()).await?;
+    Ok((Arc::clone(shared), stream))
+}
+
+struct PeerConn {
+    /// Key of the connection in `State::peers`.
+    id: u64,
+    framed: Framed<TcpStream, MessageCodec>,
+    has: Bitfield,
+    /// The peer chokes us.
+    choked: bool,
+    /// We choke the peer.
+    am_choking: bool,
+    am_interested: bool,
+    /// Requests sent to the peer that it has not answered yet.
+    in_flight: Vec<Block>,
+    /// When the peer last answered one of our requests.
+    last_block: Instant,
+    /// Number of requests we try to keep in flight, sized from `rate`.
+    queue_depth: usize,
+    /// Download rate from the peer in bytes per second, measured over `window`.
+    rate: f64,
+    window: (Instant, u64),
+}
+
+/// Runs a connection to `addr`, over `stream` if the peer connected to us.
+async fn run_peer(
+    shared: Arc<Shared>,
+    addr: SocketAddr,
+    stream: Option<TcpStream>,
+) -> anyhow::Result<()> {
+    let mut conn = match stream {
+        Some(stream) => PeerConn::new(&shared, stream),
+        None => timeout(CONNECT_TIMEOUT, PeerConn::connect(&shared, addr))
+            .await
+            .context("connect timed out")??,
+    };
+
+    let res = conn.run(&shared).await;
+    shared.abort(&conn.in_flight);
+    let mut state = shared.state.lock().unwrap();
+    state.picker.remove_bitfield(&conn.has);
+    state.peers.remove(&conn.id);
+    res
+}
+
+impl PeerConn {
+    async fn connect(shared: &Shared, addr: SocketAddr) -> anyhow::Result<Self> {
+        let mut stream = TcpStream::connect(addr).await.context("connect to peer")?;
+
+        let handshake = Handshake::new(shared.info_hash, shared.peer_id);
+        let mut handshake_b = handshake.to_bytes();
+        stream.write_all(&handshake_b).await?;
+        stream.read_exact(&mut handshake_b).await?;
+        let handshake = Handshake::from_bytes(&handshake_b);
+        if &handshake.msg != b"BitTorrent protocol" {
+            bail!("peer sent an invalid handshake");
+        }
+        if handshake.info_hash != shared.info_hash {
+            bail!("peer answered with another info hash");
+        }
+        Ok(Self::new(shared, stream))
+    }
+
+    /// A connection over a stream whose handshake is done.
+    fn new(shared: &Shared, stream: TcpStream) -> Self {
+        let pieces = shared.torrent.info().pieces.0.len();
+        let limits = shared
+            .config
+            .frame_limits
+            .unwrap_or_else(|| FrameLimits::for_pieces(pieces));
+        PeerConn {
+            id: shared.next_conn_id.fetch_add(1, Ordering::Relaxed),
+            framed: Framed::new(stream, MessageCodec::new(limits)),
+            has: Bitfield::new(pieces),
+            choked: true,
+            am_choking: true,
+            am_interested: false,
+            in_flight: Vec::new(),
+            last_block: Instant::now(),
+            queue_depth: shared.config.min_requests,
+            rate: 0.0,
+            window: (Instant::now(), 0),
+        }
+    }
+
+    /// Trades blocks with the peer until the connection fails, or until neither of us needs
+    /// anything from the other.
+    async fn run(&mut self, shared: &Shared) -> anyhow::Result<()> {
+        let mut done = shared.done.subscribe();
+        let mut received = shared.received.subscribe();
+        let mut completed = shared.have.subscribe();
+        // Peers stay choked until the choker says otherwise.
+        let (unchoke, mut unchoke_rx) = watch::channel(false);
+        shared.state.lock().unwrap().peers.insert(
+            self.id,
+            PeerStatus {
+                interested: false,
+                downloaded: 0,
+                uploaded: 0,
+                unchoke,
+            },
+        );
+
+        let mut keepalive = tokio::time::interval_at(
+            tokio::time::Instant::now() + KEEPALIVE_INTERVAL,
+            KEEPALIVE_INTERVAL,
+        );
+
+        let have = shared.state.lock().unwrap().verified.clone();
+        if have.count() > 0 {
+            self.framed
+                .send(PeerMessage::Bitfield(have))
+                .await
+                .context("send bitfield")?;
+        }
+
+        loop {
+            let seeding = *done.borrow();
+            if seeding && self.has.is_full() {
+                return Ok(());
+            }
+            if self.am_interested == seeding {
+                self.am_interested = !seeding;
+                let msg = if seeding {
+                    PeerMessage::NotInterested
+                } else {
+                    PeerMessage::Interested
+                };
+                self.framed.send(msg).await.context("send interest")?;
+            }
+            if !self.choked && self.am_interested {
+                self.fill_pipeline(shared).await?;
+            }
+
+            let wait = if self.in_flight.is_empty() {
+                IDLE_TIMEOUT
+            } else {
+                BLOCK_TIMEOUT.saturating_sub(self.last_block.elapsed())
+            };
+            tokio::select! {
+                msg = timeout(wait, self.recv()) => {
+                    let msg = msg.context("peer went silent")??;
+                    self.handle(shared, msg).await?;
+                }
+                block = received.recv() => {
+                    if let Ok(block) = block {
+                        self.cancel(shared, block).await?;
+                    }
+                }
+                piece = completed.recv() => {
+                    if let Ok(piece_i) = piece {
+                        self.framed
+                            .send(PeerMessage::Have(piece_i as u32))
+                            .await
+                            .context("send have")?;
+                    }
+                }
+                _ = unchoke_rx.changed() => {
+                    let unchoke = *unchoke_rx.borrow_and_update();
+                    if unchoke == self.am_choking {
+                        self.am_choking = !unchoke;
+                        let msg = if unchoke {
+                            PeerMessage::Unchoke
+                        } else {
+                            PeerMessage::Choke
+                        };
+                        self.framed
+                            .send(msg)
+                            .await
+                            .context("send choke")?;
+                    }
+                }
+                _ = keepalive.tick() => {
+                    self.framed
+                        .send(PeerMessage::KeepAlive)
+                        .await
+                        .context("send keepalive")?;
+                }
+                _ = done.changed() => {}
+            }
+        }
+    }
+
+    /// Sends requests until `queue_depth` of them are in flight or there is nothing left that
+    /// the peer can give us.
+    async fn fill_pipeline(&mut self, shared: &Shared) -> anyhow::Result<()> {
+        if self.in_flight.is_empty() {
+            self.last_block = Instant::now();
+        }
+        while self.in_flight.len() < self.queue_depth {
+            let Some(block) = shared.pick_block(&self.has, &self.in_flight) else {
+                break;
+            };
+            self.in_flight.push(block);
+
+            self.framed
+                .feed(PeerMessage::Request {
+                    index: block.piece as u32,
+                    begin: block.begin,
+                    length: block.length,
+                })
+                .await
+                .context("send request")?;
+        }
+        self.framed.flush().await.context("send requests")?;
+        Ok(())
+    }
+
+    /// Cancels our request for `block` if we have one in flight, another peer already sent it.
+    async fn cancel(&mut self, shared: &Shared, block: Block) -> anyhow::Result<()> {
+        let Some(pos) = self.in_flight.iter().position(|b| *b == block) else {
+            return Ok(());
+        };
+        self.in_flight.swap_remove(pos);
+        shared.abort(&[block]);
+
+        self.framed
+            .send(PeerMessage::Cancel {
+                index: block.piece as u32,
+                begin: block.begin,
+                length: block.length,
+            })
+            .await
+            .context("send cancel")
+    }
+
+    async fn recv(&mut self) -> anyhow::Result<PeerMessage> {
+        self.framed
+            .next()
+            .await
+            .context("peer closed the connection")?
+            .context("peer msg was invalid")
+    }
+
+    /// Updates the connection state from a peer message, and keeps the piece availability of the
+    /// swarm in sync with what the peer announces.
+    async fn handle(&mut self, shared: &Shared, msg: PeerMessage) -> anyhow::Result<()> {
+        match msg {
+            PeerMessage::Choke => {
+                // The peer drops our pending requests when it chokes us.
+                self.choked = true;
+                shared.abort(&self.in_flight);
+                self.in_flight.clear();
+            }
+            PeerMessage::Unchoke => self.choked = false,
+            PeerMessage::Interested => {
+                let mut state = shared.state.lock().unwrap();
+                let unchoked = state.peers.values().filter(|s| *s.unchoke.borrow()).count();
+                let status = state
+                    .peers
+                    .get_mut(&self.id)
+                    .expect("connection is registered");
+                status.interested = true;
+                // Free slots are handed out right away rather than at the next round.
+                if unchoked < shared.config.unchoke_slots {
+                    status.unchoke.send_replace(true);
+                }
+            }
+            PeerMessage::NotInterested => {
+                let mut state = shared.state.lock().unwrap();
+                if let Some(status) = state.peers.get_mut(&self.id) {
+                    status.interested = false;
+                }
+            }
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } => self.serve(shared, index as usize, begin, length).await?,
+            PeerMessage::Have(index) => {
+                let piece_i = index as usize;
+                if piece_i < self.has.len() && !self.has.has(piece_i) {
+                    self.has.set(piece_i);
+                    shared.state.lock().unwrap().picker.add_have(piece_i);
+                }
+            }
+            PeerMessage::Bitfield(has) => {
+                let has = Bitfield::from_bytes(has.into_bytes(), self.has.len());
+                let mut state = shared.state.lock().unwrap();
+                state.picker.remove_bitfield(&self.has);
+                state.picker.add_bitfield(&has);
+                self.has = has;
+            }
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => self.receive(shared, index as usize, begin, block).await?,
+            // Receiving it restarted the idle timeout, there is nothing else to do.
+            PeerMessage::KeepAlive => {}
+            // We answer requests as they come in, there is nothing queued to cancel.
+            PeerMessage::Cancel { .. }
+            | PeerMessage::Extended { .. }
+            | PeerMessage::Unknown { .. } => {}
+        }
+        Ok(())
+    }
+
+    async fn receive(
+        &mut self,
+        shared: &Shared,
+        piece_i: usize,
+        begin: u32,
+        data: Bytes,
+    ) -> anyhow::Result<()> {
+        let block = Block {
+            piece: piece_i,
+            begin,
+            length: data.len() as u32,
+        };
+        // Answers to requests we aborted on a choke are still welcome if nobody else sent them.
+        if let Some(pos) = self.in_flight.iter().position(|b| *b == block) {
+            self.in_flight.swap_remove(pos);
+        } else if !shared.state.lock().unwrap().picker.is_block(&block) {
+            bail!("peer sent a block we did not ask for");
+        }
+        self.last_block = Instant::now();
+        self.update_rate(shared, block.length);
+        if let Some(status) = shared.state.lock().unwrap().peers.get_mut(&self.id) {
+            status.downloaded += block.length as u64;
+        }
+        shared
+            .downloaded
+            .fetch_add(block.length as u64, Ordering::Relaxed);
+
+        // In endgame another peer can deliver the same block, only the first copy is written so
+        // nothing lands in a piece after it was verified.
+        if !shared.claim(&block) {
+            return Ok(());
+        }
+
+        // Disk I/O and hashing block, keep them off the runtime threads.
+        let storage = Arc::clone(&shared.storage);
+        let written = tokio::task::spawn_blocking(move || {
+            storage.write_block(block.piece, block.begin, &data)
+        })
+        .await;
+        if !matches!(written, Ok(Ok(()))) {
+            shared.release(&block);
+        }
+        written??;
+        if !shared.block_received(&block) {
+            return Ok(());
+        }
+
+        let piece_i = block.piece;
+        let storage = Arc::clone(&shared.storage);
+        match tokio::task::spawn_blocking(move || storage.verify_piece(piece_i)).await? {
+            Ok(true) => {
+                shared.complete(piece_i);
+                Ok(())
+            }
+            Ok(false) => {
+                shared.piece_failed(piece_i);
+                bail!("hash mismatch for piece {piece_i}");
+            }
+            Err(e) => {
+                shared.piece_failed(piece_i);
+                Err(e)
+            }
+        }
+    }
+
+    /// Answers a request of the peer with the block, unless we choke it.
+    async fn serve(
+        &mut self,
+        shared: &Shared,
+        piece_i: usize,
+        begin: u32,
+        length: u32,
+    ) -> anyhow::Result<()> {
+        // Requests the peer sent before it saw our choke are dropped.
+        if self.am_choking {
+            return Ok(());
+        }
+        if piece_i >= self.has.len()
+            || length as u64 > BLOCK_MAX
+            || begin as u64 + length as u64 > shared.torrent.info().piece_size(piece_i)
+        {
+            bail!("peer sent an invalid request");
+        }
+        if !shared.has_piece(piece_i) {
+            bail!("peer requested piece {piece_i} we do not have");
+        }
+
+        let storage = Arc::clone(&shared.storage);
+        let block = tokio::task::spawn_blocking(move || storage.read_block(piece_i, begin, length))
+            .await??;
+        self.framed
+            .send(PeerMessage::Piece {
+                index: piece_i as u32,
+                begin,
+                block: block.into(),
+            })
+            .await
+            .context("send piece")?;
+        shared.uploaded.fetch_add(length as u64, Ordering::Relaxed);
+        if let Some(status) = shared.state.lock().unwrap().peers.get_mut(&self.id) {
+            status.uploaded += length as u64;
+        }
+        Ok(())
+    }
+
+    /// Resizes the pipeline so it holds `request_queue_time` worth of blocks at the current rate.
+    fn update_rate(&mut self, shared: &Shared, received: u32) {
+        let (start, bytes) = &mut self.window;
+        *bytes += received as u64;
+        let elapsed = start.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            return;
+        }
+        let sample = *bytes as f64 / elapsed.as_secs_f64();
+        self.rate = if self.rate == 0.0 {
+            sample
+        } else {
+            0.7 * self.rate + 0.3 * sample
+        };
+        self.window = (Instant::now(), 0);
+
+        let config = &shared.config;
+        let depth = self.rate * config.request_queue_time.as_secs_f64() / BLOCK_MAX as f64;
+        self.queue_depth = (depth as usize).clamp(config.min_requests, config.max_requests);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha1::{Digest, Sha1};
+
+    use super::*;
+    use crate::{
+        storage::MemoryStorage,
+        torrent::{Hashes, Info, Keys},
+    };
+
+    const PLENGTH: usize = 32768;
+
+    /// A torrent of five pieces, the last one short, and its data.
+    fn torrent() -> (Torrent, Vec<u8>) {
+        let data: Vec<u8> = (0..150_000u32).map(|i| (i * 13 % 251) as u8).collect();
+        let info = Info {
+            name: "a".to_string(),
+            plength: PLENGTH as u64,
+            pieces: Hashes(
+                data.chunks(PLENGTH)
+                    .map(|c| Sha1::digest(c).into())
+                    .collect(),
+            ),
+            private: None,
+            keys: Keys::SingleFile {
+                length: data.len() as u64,
+            },
+        };
+        (Torrent::new(info).unwrap(), data)
+    }
+
+    fn peer_id(n: u8) -> [u8; 20] {
+        let mut id = *b"-RS0001-000000000000";
+        id[19] = n;
+        id
+    }
+
+    /// A swarm that has checked the whole of `data` in its storage.
+    async fn seeder(torrent: &Torrent, data: &[u8], n: u8) -> Swarm {
+        let storage = MemoryStorage::new(torrent.info());
+        for (piece_i, piece) in data.chunks(PLENGTH).enumerate() {
+            storage.write_block(piece_i, 0, piece).unwrap();
+        }
+        let swarm = Swarm::new(torrent.clone(), peer_id(n), Arc::new(storage));
+        let npieces = torrent.info().pieces.0.len();
+        let verified = swarm.recheck(&Bitfield::new(npieces)).await.unwrap();
+        assert_eq!(verified, npieces);
+        assert!(*swarm.done().borrow());
+        swarm
+    }
+
+    fn read_all(storage: &dyn Storage, len: usize) -> Vec<u8> {
+        (0..len.div_ceil(PLENGTH))
+            .flat_map(|piece_i| {
+                let length = PLENGTH.min(len - piece_i * PLENGTH) as u32;
+                storage.read_block(piece_i, 0, length).unwrap()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn downloads_from_a_listening_seeder() {
+        let (torrent, data) = torrent();
+        let seeder = seeder(&torrent, &data, 1).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let storage = Arc::new(MemoryStorage::new(torrent.info()));
+        let config = Config {
+            min_requests: 1,
+            max_requests: 2,
+            ..Config::default()
+        };
+        let leecher = Swarm::with_config(torrent, peer_id(2), storage.clone(), config);
+        let download =
+            async { timeout(Duration::from_secs(30), leecher.download(vec![addr])).await };
+        let seeders = [&seeder];
+        tokio::select! {
+            r = listen(listener, &seeders) => panic!("listen returned {r:?}"),
+            r = seeder.seed(Vec::new()) => panic!("seed returned {r:?}"),
+            r = download => r.expect("download timed out").unwrap(),
+        }
+
+        assert!(*leecher.done().borrow());
+        assert_eq!(leecher.stats().left, 0);
+        assert_eq!(leecher.stats().downloaded, data.len() as u64);
+        assert_eq!(read_all(&*storage, data.len()), data);
+        assert_eq!(seeder.stats().uploaded, data.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn downloads_from_a_seeder_we_connect_to_and_one_that_connects_to_us() {
+        let (torrent, data) = torrent();
+        let (seeder1, seeder2) = (
+            seeder(&torrent, &data, 1).await,
+            seeder(&torrent, &data, 2).await,
+        );
+        let listener1 = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr1 = listener1.local_addr().unwrap();
+        // The leecher listens too, for the second seeder to connect to it.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let storage = Arc::new(MemoryStorage::new(torrent.info()));
+        let leecher = Swarm::new(torrent, peer_id(3), storage.clone());
+        let download =
+            async { timeout(Duration::from_secs(30), leecher.download(vec![addr1])).await };
+        let (seeders, leechers) = ([&seeder1], [&leecher]);
+        tokio::select! {
+            r = listen(listener1, &seeders) => panic!("listen returned {r:?}"),
+            r = listen(listener, &leechers) => panic!("listen returned {r:?}"),
+            r = seeder1.seed(Vec::new()) => panic!("seed returned {r:?}"),
+            r = seeder2.seed(vec![addr]) => panic!("seed returned {r:?}"),
+            r = download => r.expect("download timed out").unwrap(),
+        }
+
+        assert_eq!(read_all(&*storage, data.len()), data);
+        // In endgame a block can come from both seeders, the copy that is dropped still counts.
+        assert!(leecher.stats().downloaded >= data.len() as u64);
+        assert_eq!(leecher.have().count(), 5);
+    }
+}
@@ -1,20 +1,136 @@
+use anyhow::{bail, Context};
 use serde::{Deserialize, Serialize};
-use serde_json::{self, Map};
+use std::path::{Component, Path, PathBuf};
 
+use crate::bencode;
 pub use hashes::Hashes;
 use sha1::{Digest, Sha1};
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// A parsed .torrent file.
+///
+/// The info dictionary is kept as the bytes it was parsed from, the info hash is computed over
+/// them. `info` is only readable so the parsed copy can never get out of step with those bytes.
+#[derive(Debug, Clone, Serialize)]
 pub struct Torrent {
+    /// Empty for torrents that only have an `announce-list`, or no trackers at all.
+    #[serde(skip_serializing_if = "String::is_empty")]
     pub announce: String,
-    pub info: Info,
+
+    #[serde(rename = "announce-list", skip_serializing_if = "Option::is_none")]
+    pub announce_list: Option<Vec<Vec<String>>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+
+    #[serde(rename = "created by", skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
+
+    /// Seconds since the epoch.
+    #[serde(rename = "creation date", skip_serializing_if = "Option::is_none")]
+    pub creation_date: Option<i64>,
+
+    /// Web seeds (BEP 19).
+    #[serde(rename = "url-list", skip_serializing_if = "Option::is_none")]
+    pub url_list: Option<Vec<String>>,
+
+    info: Info,
+
+    /// The info dictionary exactly as it was parsed, including the keys `Info` does not model.
+    #[serde(skip)]
+    raw_info: Vec<u8>,
+}
+
+/// The layout of a .torrent file, only used to parse one into a [`Torrent`].
+#[derive(Deserialize)]
+struct Metainfo {
+    #[serde(default)]
+    announce: String,
+    #[serde(rename = "announce-list", default)]
+    announce_list: Option<Vec<Vec<String>>>,
+    #[serde(default)]
+    comment: Option<String>,
+    #[serde(rename = "created by", default)]
+    created_by: Option<String>,
+    #[serde(rename = "creation date", default)]
+    creation_date: Option<i64>,
+    /// Some clients write a single string instead of a list.
+    #[serde(
+        rename = "url-list",
+        default,
+        deserialize_with = "string_or_list::deserialize"
+    )]
+    url_list: Option<Vec<String>>,
+    info: Info,
 }
 
 impl Torrent {
+    /// Builds a torrent around `info`, encoding it to get the bytes the info hash is computed
+    /// over. The other keys start out empty.
+    pub fn new(info: Info) -> anyhow::Result<Self> {
+        info.check()?;
+        let raw_info = serde_bencode::to_bytes(&info).context("encode info dictionary")?;
+        Ok(Torrent {
+            announce: String::new(),
+            announce_list: None,
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            url_list: None,
+            info,
+            raw_info,
+        })
+    }
+
+    /// Parses a .torrent file, keeping the original bytes of the info dictionary.
+    pub fn from_bytes(data: &[u8]) -> anyhow::Result<Self> {
+        let metainfo: Metainfo = serde_bencode::from_bytes(data)?;
+        metainfo.info.check()?;
+        let span =
+            bencode::dict_value_span(data, b"info").context("torrent has no info dictionary")?;
+        Ok(Torrent {
+            announce: metainfo.announce,
+            announce_list: metainfo.announce_list,
+            comment: metainfo.comment,
+            created_by: metainfo.created_by,
+            creation_date: metainfo.creation_date,
+            url_list: metainfo.url_list,
+            info: metainfo.info,
+            raw_info: data[span].to_vec(),
+        })
+    }
+
+    /// Builds a torrent around the bytes of an info dictionary, e.g. fetched from peers.
+    pub fn from_info_bytes(announce: String, info_bytes: Vec<u8>) -> anyhow::Result<Self> {
+        let info: Info = serde_bencode::from_bytes(&info_bytes)?;
+        info.check()?;
+        Ok(Torrent {
+            announce,
+            announce_list: None,
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            url_list: None,
+            info,
+            raw_info: info_bytes,
+        })
+    }
+
+    /// Encodes the torrent, with the original info dictionary.
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let mut data = serde_bencode::to_bytes(self)?;
+        let span = bencode::dict_value_span(&data, b"info").expect("info was just encoded");
+        data.splice(span, self.raw_info.iter().copied());
+        Ok(data)
+    }
+
+    pub fn info(&self) -> &Info {
+        &self.info
+    }
+
+    /// SHA-1 of the original info dictionary.
     pub fn info_hash(&self) -> [u8; 20] {
-        let info_bencoded = serde_bencode::to_bytes(&self.info).expect("re-encode info");
         let mut hasher = Sha1::new();
-        hasher.update(info_bencoded);
+        hasher.update(&self.raw_info);
         hasher.finalize().into()
     }
 }
@@ -27,10 +143,103 @@ pub struct Info {
     pub plength: u64,
     pub pieces: Hashes,
 
+    /// Set to 1 to keep peers to the trackers of the torrent (BEP 27).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+
     #[serde(flatten)]
     pub keys: Keys,
 }
 
+impl Info {
+    /// Rejects piece layouts the rest of the code cannot work with.
+    fn check(&self) -> anyhow::Result<()> {
+        if self.plength == 0 {
+            bail!("torrent has a piece length of 0");
+        }
+        if self.pieces.0.is_empty() {
+            bail!("torrent has no pieces");
+        }
+        let expected = self.length().div_ceil(self.plength);
+        if self.pieces.0.len() as u64 != expected {
+            bail!(
+                "torrent has {} piece hashes for {expected} pieces",
+                self.pieces.0.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// Total number of bytes in the torrent, summed over all files.
+    pub fn length(&self) -> u64 {
+        match &self.keys {
+            Keys::SingleFile { length } => *length,
+            Keys::MultiFile { files } => files.iter().map(|f| f.length).sum(),
+        }
+    }
+
+    /// Size of piece `piece_i`; the last piece can be smaller than `plength`.
+    pub fn piece_size(&self, piece_i: usize) -> u64 {
+        if piece_i == self.pieces.0.len() - 1 {
+            let md = self.length() % self.plength;
+            if md == 0 {
+                self.plength
+            } else {
+                md
+            }
+        } else {
+            self.plength
+        }
+    }
+
+    /// Maps the contiguous piece space onto files under `output`.
+    ///
+    /// A single-file torrent is written to `output` itself, a multi-file torrent uses `output` as
+    /// the root directory for every `File::path`.
+    pub fn layout(&self, output: &Path) -> anyhow::Result<Vec<FileSpan>> {
+        match &self.keys {
+            Keys::SingleFile { length } => Ok(vec![FileSpan {
+                path: output.to_path_buf(),
+                offset: 0,
+                length: *length,
+            }]),
+            Keys::MultiFile { files } => {
+                let mut offset = 0;
+                let mut spans = Vec::with_capacity(files.len());
+                for file in files {
+                    if file.path.is_empty() {
+                        bail!("file entry with an empty path in torrent");
+                    }
+                    let mut path = output.to_path_buf();
+                    for part in &file.path {
+                        // Reject anything that could escape the output directory.
+                        let mut components = Path::new(part).components();
+                        match (components.next(), components.next()) {
+                            (Some(Component::Normal(c)), None) => path.push(c),
+                            _ => bail!("invalid path component {part:?} in torrent"),
+                        }
+                    }
+                    spans.push(FileSpan {
+                        path,
+                        offset,
+                        length: file.length,
+                    });
+                    offset += file.length;
+                }
+                Ok(spans)
+            }
+        }
+    }
+}
+
+/// A file on disk and the byte range of the piece space it covers.
+#[derive(Debug, Clone)]
+pub struct FileSpan {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub length: u64,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum Keys {
@@ -45,6 +254,28 @@ pub struct File {
     pub path: Vec<String>,
 }
 
+mod string_or_list {
+    use serde::{Deserialize, Deserializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        String(String),
+        List(Vec<String>),
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(match StringOrList::deserialize(deserializer)? {
+            StringOrList::String(s) if s.is_empty() => None,
+            StringOrList::String(s) => Some(vec![s]),
+            StringOrList::List(l) => Some(l),
+        })
+    }
+}
+
 mod hashes {
     use serde::de::{self, Deserialize, Deserializer, Visitor};
     use serde::ser::{Serialize, Serializer};
@@ -66,7 +297,7 @@ mod hashes {
         where
             E: de::Error,
         {
-            if v.len() % 20 != 0 {
+            if !v.len().is_multiple_of(20) {
                 return Err(E::custom(format!("length is {}", v.len())));
             }
             Ok(Hashes(
@@ -96,3 +327,62 @@ mod hashes {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO: &[u8] =
+        b"d6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:unknowni1ee";
+
+    fn sha1(data: &[u8]) -> [u8; 20] {
+        Sha1::digest(data).into()
+    }
+
+    #[test]
+    fn info_hash_covers_keys_info_does_not_model() {
+        let data = [b"d8:announce3:url4:info".as_slice(), INFO, b"e"].concat();
+        let t = Torrent::from_bytes(&data).unwrap();
+        assert_eq!(t.info_hash(), sha1(INFO));
+        assert_eq!(t.info().length(), 5);
+
+        let reparsed = Torrent::from_bytes(&t.to_bytes().unwrap()).unwrap();
+        assert_eq!(reparsed.info_hash(), t.info_hash());
+        assert_eq!(reparsed.announce, "url");
+    }
+
+    #[test]
+    fn announce_list_alone_is_enough() {
+        let data = [b"d13:announce-listll3:urlee4:info".as_slice(), INFO, b"e"].concat();
+        let t = Torrent::from_bytes(&data).unwrap();
+        assert_eq!(t.announce, "");
+        assert_eq!(t.announce_list, Some(vec![vec!["url".to_string()]]));
+    }
+
+    #[test]
+    fn rejects_unusable_piece_layouts() {
+        for info in [
+            b"d6:lengthi5e4:name1:a12:piece lengthi0e6:pieces20:aaaaaaaaaaaaaaaaaaaae".as_slice(),
+            b"d6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces0:e",
+            b"d6:lengthi0e4:name1:a12:piece lengthi16384e6:pieces0:e",
+            b"d6:lengthi16385e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+        ] {
+            let data = [b"d4:info".as_slice(), info, b"e"].concat();
+            assert!(Torrent::from_bytes(&data).is_err());
+            assert!(Torrent::from_info_bytes(String::new(), info.to_vec()).is_err());
+        }
+    }
+
+    #[test]
+    fn piece_size_of_the_last_piece() {
+        let data = [b"d4:info".as_slice(), INFO, b"e"].concat();
+        let t = Torrent::from_bytes(&data).unwrap();
+        assert_eq!(t.info().piece_size(0), 5);
+    }
+
+    #[test]
+    fn from_info_bytes_hashes_the_given_bytes() {
+        let t = Torrent::from_info_bytes("url".to_string(), INFO.to_vec()).unwrap();
+        assert_eq!(t.info_hash(), sha1(INFO));
+    }
+}
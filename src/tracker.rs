@@ -1,9 +1,43 @@
-use peers::Peers;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    net::IpAddr,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
 
+use anyhow::{bail, Context};
+use peers::Peers;
+use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
 
+use crate::torrent::Torrent;
+use udp::UdpTracker;
+
+pub mod server;
+pub mod udp;
+
+/// Time an HTTP tracker gets to accept the connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time an HTTP tracker gets to answer, so a hung tracker does not hold up the next one.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The client every HTTP tracker request goes through, sharing its connection pool.
+fn http_client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("build http client")
+    })
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct TrackerRequest {
+    /// Sent percent-encoded byte by byte, serde would treat it as a sequence.
+    #[serde(skip)]
+    pub info_hash: [u8; 20],
     pub peer_id: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ip: Option<String>,
@@ -14,84 +48,711 @@ pub struct TrackerRequest {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub event: Option<Event>,
     pub compact: u8,
+    /// Number of peers we want, the tracker picks when it is left out.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub numwant: Option<u32>,
+    /// Identifies us to the tracker if our IP changes, never shared with peers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<u32>,
+    /// Asks for peer lists without peer ids, ignored when `compact` is set.
+    #[serde(skip_serializing_if = "std::ops::Not::not", serialize_with = "flag")]
+    pub no_peer_id: bool,
+    /// We can use encrypted connections.
+    #[serde(skip_serializing_if = "std::ops::Not::not", serialize_with = "flag")]
+    pub supportcrypto: bool,
+    /// The `tracker id` of an earlier response, filled in by `TrackerList`.
+    #[serde(rename = "trackerid", skip_serializing_if = "Option::is_none")]
+    pub tracker_id: Option<String>,
 }
 
 impl TrackerRequest {
+    /// A compact request for the torrent with `info_hash`, before anything was transferred.
+    pub fn new(info_hash: [u8; 20], peer_id: String, port: u16, left: u64) -> Self {
+        TrackerRequest {
+            info_hash,
+            peer_id,
+            ip: None,
+            port,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            event: None,
+            compact: 1,
+            numwant: None,
+            key: None,
+            no_peer_id: false,
+            supportcrypto: false,
+            tracker_id: None,
+        }
+    }
+
     pub fn http_query_params(&self) -> String {
-        serde_urlencoded::to_string(self).unwrap()
+        format!(
+            "info_hash={}&{}",
+            percent_encode(&self.info_hash),
+            serde_urlencoded::to_string(self).unwrap()
+        )
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
+/// Boolean query parameters are sent as `1` and left out when false.
+fn flag<S: serde::Serializer>(_: &bool, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u8(1)
+}
+
+/// Percent-encodes every byte outside the unreserved characters of RFC 3986.
+fn percent_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(3 * bytes.len());
+    for &byte in bytes {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push('%');
+            encoded.push_str(&hex::encode_upper([byte]));
+        }
+    }
+    encoded
+}
+
+/// Announces to a single HTTP tracker.
+pub async fn announce(url: &str, request: &TrackerRequest) -> anyhow::Result<TrackerResponse> {
+    // Some trackers carry a passkey in the query of their announce URL.
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let tracker_url = format!("{}{}{}", url, separator, request.http_query_params());
+    let response = http_client()
+        .get(tracker_url)
+        .send()
+        .await
+        .context("tracker url response")?;
+    let response = response.bytes().await.context("get response bytes")?;
+
+    serde_bencode::from_bytes(&response).context("deserialize response struct")
+}
+
+/// Swarm counts a tracker reports for one torrent. Trackers leave out counts they do not keep,
+/// those read as 0.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ScrapeStats {
+    /// Peers with the whole torrent.
+    pub complete: u64,
+    /// Peers still downloading.
+    pub incomplete: u64,
+    /// Times the tracker saw the torrent completed.
+    pub downloaded: u64,
+}
+
+/// The scrape URL of an announce URL whose last path segment starts with `announce`, as
+/// described in BEP 48.
+pub fn scrape_url(announce: &str) -> Option<String> {
+    let slash = announce.rfind('/')?;
+    let rest = announce[slash + 1..].strip_prefix("announce")?;
+    Some(format!("{}scrape{}", &announce[..=slash], rest))
+}
+
+/// Scrapes several torrents from one tracker, over UDP for `udp://` URLs and HTTP otherwise.
+///
+/// `url` is the announce URL. Torrents the tracker does not know are missing from the result.
+pub async fn scrape(
+    url: &str,
+    info_hashes: &[[u8; 20]],
+) -> anyhow::Result<HashMap<[u8; 20], ScrapeStats>> {
+    if url.starts_with("udp://") {
+        let stats = UdpTracker::new(url)?.scrape(info_hashes).await?;
+        return Ok(info_hashes.iter().copied().zip(stats).collect());
+    }
+
+    #[derive(Deserialize)]
+    struct ScrapeResponse {
+        #[serde(default)]
+        files: HashMap<serde_bytes::ByteBuf, ScrapeStats>,
+        #[serde(rename = "failure reason")]
+        fail_reason: Option<String>,
+    }
+
+    let mut tracker_url = scrape_url(url).context("tracker does not support scrape")?;
+    for (i, info_hash) in info_hashes.iter().enumerate() {
+        let separator = if i == 0 && !tracker_url.contains('?') {
+            '?'
+        } else {
+            '&'
+        };
+        tracker_url.push(separator);
+        tracker_url.push_str("info_hash=");
+        tracker_url.push_str(&percent_encode(info_hash));
+    }
+    let response = http_client()
+        .get(tracker_url)
+        .send()
+        .await
+        .context("tracker url response")?;
+    let response = response.bytes().await.context("get response bytes")?;
+    let response: ScrapeResponse =
+        serde_bencode::from_bytes(&response).context("deserialize scrape response")?;
+    if let Some(fail_reason) = response.fail_reason {
+        bail!("{fail_reason}");
+    }
+    Ok(response
+        .files
+        .into_iter()
+        .filter_map(|(info_hash, stats)| Some((info_hash.as_slice().try_into().ok()?, stats)))
+        .collect())
+}
+
+/// The trackers of a torrent, grouped in tiers (BEP 12).
+///
+/// Trackers are tried in order within a tier before moving to the next tier, and a tracker that
+/// answers moves to the front of its tier so it is tried first next time. `udp://` trackers are
+/// announced to with BEP 15, everything else over HTTP.
+///
+/// The `tracker id` each tracker hands out is sent back on later announces to it, and regular
+/// announces (without an event) wait out the `min interval` of the last response.
+#[derive(Debug)]
+pub struct TrackerList {
+    tiers: Vec<Vec<String>>,
+    udp: HashMap<String, UdpTracker>,
+    tracker_ids: HashMap<String, String>,
+    /// When the last announce succeeded, with its interval and min interval.
+    last: Option<(Instant, Duration, Option<Duration>)>,
+}
+
+impl TrackerList {
+    /// The `announce-list` of the torrent, or its `announce` URL if it has none.
+    pub fn new(torrent: &Torrent) -> Self {
+        match &torrent.announce_list {
+            Some(tiers) if tiers.iter().any(|tier| !tier.is_empty()) => {
+                Self::from_tiers(tiers.clone())
+            }
+            _ if torrent.announce.is_empty() => Self::from_tiers(Vec::new()),
+            _ => Self::from_tiers(vec![vec![torrent.announce.clone()]]),
+        }
+    }
+
+    /// Shuffles the trackers within each tier, as BEP 12 asks clients to do once on load.
+    pub fn from_tiers(mut tiers: Vec<Vec<String>>) -> Self {
+        tiers.retain(|tier| !tier.is_empty());
+        let mut rng = rand::thread_rng();
+        for tier in &mut tiers {
+            tier.shuffle(&mut rng);
+        }
+        TrackerList {
+            tiers,
+            udp: HashMap::new(),
+            tracker_ids: HashMap::new(),
+            last: None,
+        }
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    /// How long to wait before the next regular announce, the interval of the last response but
+    /// never less than its min interval.
+    pub fn interval(&self) -> Option<Duration> {
+        let (_, interval, min_interval) = self.last?;
+        Some(interval.max(min_interval.unwrap_or_default()))
+    }
+
+    /// Announces to the first tracker that answers without a failure reason.
+    pub async fn announce(&mut self, request: &TrackerRequest) -> anyhow::Result<TrackerResponse> {
+        if let (None, Some((at, _, Some(min_interval)))) = (&request.event, self.last) {
+            tokio::time::sleep_until((at + min_interval).into()).await;
+        }
+
+        let mut errors = Vec::new();
+        for tier in &mut self.tiers {
+            for i in 0..tier.len() {
+                let mut request = request.clone();
+                request.tracker_id = self.tracker_ids.get(&tier[i]).cloned();
+                let res = announce_one(&mut self.udp, &tier[i], &request).await;
+                let res = match res {
+                    Ok(TrackerResponse {
+                        resp_type: ResponseType::Err { fail_reason },
+                    }) => Err(anyhow::anyhow!("{fail_reason}")),
+                    res => res,
+                };
+                match res {
+                    Ok(response) => {
+                        if let ResponseType::Ok {
+                            interval,
+                            min_interval,
+                            tracker_id,
+                            warning,
+                            ..
+                        } = &response.resp_type
+                        {
+                            if let Some(warning) = warning {
+                                eprintln!("{}: warning: {warning}", tier[i]);
+                            }
+                            if let Some(tracker_id) = tracker_id {
+                                self.tracker_ids.insert(tier[i].clone(), tracker_id.clone());
+                            }
+                            self.last = Some((
+                                Instant::now(),
+                                Duration::from_secs(*interval as u64),
+                                min_interval.map(|m| Duration::from_secs(m as u64)),
+                            ));
+                        }
+                        let url = tier.remove(i);
+                        tier.insert(0, url);
+                        return Ok(response);
+                    }
+                    Err(e) => errors.push(format!("{}: {e:#}", tier[i])),
+                }
+            }
+        }
+        if errors.is_empty() {
+            bail!("torrent has no trackers");
+        }
+        bail!("no tracker answered:\n{}", errors.join("\n"))
+    }
+}
+
+/// Announces to a single tracker, over UDP for `udp://` URLs and HTTP otherwise.
+async fn announce_one(
+    udp: &mut HashMap<String, UdpTracker>,
+    url: &str,
+    request: &TrackerRequest,
+) -> anyhow::Result<TrackerResponse> {
+    if !url.starts_with("udp://") {
+        return announce(url, request).await;
+    }
+    let tracker = match udp.entry(url.to_string()) {
+        Entry::Occupied(e) => e.into_mut(),
+        Entry::Vacant(e) => e.insert(UdpTracker::new(url)?),
+    };
+    tracker.announce(request).await
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Event {
     Started,
     Completed,
     Stopped,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct TrackerResponse {
     #[serde(flatten)]
     pub resp_type: ResponseType,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum ResponseType {
+    /// Tried first, a failure can also carry fields of a success such as `interval` and would
+    /// otherwise parse as `Ok` without peers.
+    Err {
+        #[serde(rename = "failure reason")]
+        fail_reason: String,
+    },
     Ok {
         #[serde(rename = "interval")]
         interval: usize,
-        #[serde(rename = "peers")]
+        /// Trackers ask clients not to announce more often than this.
+        #[serde(
+            rename = "min interval",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        min_interval: Option<usize>,
+        /// To be sent back as `trackerid` on the next announces.
+        #[serde(
+            rename = "tracker id",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        tracker_id: Option<String>,
+        #[serde(
+            rename = "warning message",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        warning: Option<String>,
+        /// Seeders.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        complete: Option<u64>,
+        /// Leechers.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        incomplete: Option<u64>,
+        /// Our address as the tracker sees it (BEP 24).
+        #[serde(
+            rename = "external ip",
+            default,
+            skip_serializing_if = "Option::is_none",
+            with = "external_ip"
+        )]
+        external_ip: Option<IpAddr>,
+        #[serde(rename = "peers", default)]
         peers: Peers,
+        #[serde(
+            rename = "peers6",
+            default,
+            skip_serializing_if = "Peers::is_empty",
+            deserialize_with = "peers::deserialize_compact6",
+            serialize_with = "peers::serialize_compact6"
+        )]
+        peers6: Peers,
     },
-    Err {
-        #[serde(rename = "failure reason")]
-        fail_reason: String,
-    },
+}
+
+mod external_ip {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde_bytes::ByteBuf;
+
+    pub fn serialize<S>(ip: &Option<IpAddr>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match ip {
+            Some(IpAddr::V4(ip)) => serializer.serialize_bytes(&ip.octets()),
+            Some(IpAddr::V6(ip)) => serializer.serialize_bytes(&ip.octets()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    /// An address in network byte order, 4 bytes for IPv4 and 16 for IPv6.
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<IpAddr>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let v = ByteBuf::deserialize(deserializer)?;
+        match v.len() {
+            4 => Ok(Some(
+                Ipv4Addr::from(<[u8; 4]>::try_from(&v[..]).unwrap()).into(),
+            )),
+            16 => Ok(Some(
+                Ipv6Addr::from(<[u8; 16]>::try_from(&v[..]).unwrap()).into(),
+            )),
+            len => Err(serde::de::Error::custom(format!(
+                "external ip length is {len}"
+            ))),
+        }
+    }
 }
 
 pub mod peers {
-    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
-    use serde::de::{Deserialize, Visitor};
+    use serde::de::{Deserialize, Deserializer, SeqAccess, Visitor};
+    use serde::ser::{Serialize, Serializer};
+    use serde_bytes::ByteBuf;
 
-    #[derive(Debug, Clone)]
-    pub struct Peers(pub Vec<SocketAddrV4>);
+    /// Peers from a tracker, in the compact IPv4 format, the compact IPv6 format of `peers6`
+    /// (BEP 7), or the original list of dictionaries.
+    #[derive(Debug, Clone, Default)]
+    pub struct Peers(pub Vec<SocketAddr>);
     struct PeersVisitor;
 
+    /// An entry of the non-compact peer list.
+    #[derive(serde::Deserialize)]
+    struct PeerDict {
+        ip: String,
+        port: u16,
+    }
+
+    impl Peers {
+        /// Decodes the compact format: 4 bytes of address followed by 2 bytes of port per peer.
+        pub fn from_compact(v: &[u8]) -> Option<Self> {
+            if !v.len().is_multiple_of(6) {
+                return None;
+            }
+            Some(Peers(
+                v.chunks_exact(6)
+                    .map(|slice_6| {
+                        SocketAddr::new(
+                            Ipv4Addr::new(slice_6[0], slice_6[1], slice_6[2], slice_6[3]).into(),
+                            u16::from_be_bytes([slice_6[4], slice_6[5]]),
+                        )
+                    })
+                    .collect(),
+            ))
+        }
+
+        /// Decodes the compact IPv6 format: 16 bytes of address followed by 2 bytes of port.
+        pub fn from_compact6(v: &[u8]) -> Option<Self> {
+            if !v.len().is_multiple_of(18) {
+                return None;
+            }
+            Some(Peers(
+                v.chunks_exact(18)
+                    .map(|slice_18| {
+                        let octets: [u8; 16] = slice_18[..16].try_into().unwrap();
+                        SocketAddr::new(
+                            Ipv6Addr::from(octets).into(),
+                            u16::from_be_bytes([slice_18[16], slice_18[17]]),
+                        )
+                    })
+                    .collect(),
+            ))
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.0.is_empty()
+        }
+
+        /// Encodes the IPv4 peers in the compact format, skipping IPv6 peers.
+        pub fn to_compact(&self) -> Vec<u8> {
+            let mut compact = Vec::with_capacity(6 * self.0.len());
+            for peer in &self.0 {
+                if let SocketAddr::V4(peer) = peer {
+                    compact.extend(peer.ip().octets());
+                    compact.extend(peer.port().to_be_bytes());
+                }
+            }
+            compact
+        }
+
+        /// Encodes the IPv6 peers in the compact `peers6` format, skipping IPv4 peers.
+        pub fn to_compact6(&self) -> Vec<u8> {
+            let mut compact = Vec::new();
+            for peer in &self.0 {
+                if let SocketAddr::V6(peer) = peer {
+                    compact.extend(peer.ip().octets());
+                    compact.extend(peer.port().to_be_bytes());
+                }
+            }
+            compact
+        }
+    }
+
     impl<'de> Visitor<'de> for PeersVisitor {
         type Value = Peers;
 
         fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-            formatter.write_str("6 bytes, the first 4 is the addr and the last 2 is the port")
+            formatter.write_str("compact peers or a list of peer dictionaries")
         }
 
         fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
         where
             E: serde::de::Error,
         {
-            if v.len() % 6 != 0 {
-                return Err(E::custom(format!("length is {}", v.len())));
+            Peers::from_compact(v).ok_or_else(|| E::custom(format!("length is {}", v.len())))
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let mut peers = Vec::new();
+            while let Some(peer) = seq.next_element::<PeerDict>()? {
+                // The ip can also be a DNS name, those peers are skipped.
+                if let Ok(ip) = peer.ip.parse::<IpAddr>() {
+                    peers.push(SocketAddr::new(ip, peer.port));
+                }
             }
-            Ok(Peers(
-                v.chunks_exact(6)
-                    .map(|slice_6| {
-                        SocketAddrV4::new(
-                            Ipv4Addr::new(slice_6[0], slice_6[1], slice_6[2], slice_6[3]),
-                            u16::from_be_bytes([slice_6[4], slice_6[5]]),
-                        )
-                    })
-                    .collect(),
-            ))
+            Ok(Peers(peers))
         }
     }
 
     impl<'de> Deserialize<'de> for Peers {
         fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
         where
-            D: serde::Deserializer<'de>,
+            D: Deserializer<'de>,
+        {
+            deserializer.deserialize_any(PeersVisitor)
+        }
+    }
+
+    /// Peers serialize to the compact IPv4 format, IPv6 peers go in `peers6`.
+    impl Serialize for Peers {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
         {
-            deserializer.deserialize_bytes(PeersVisitor)
+            serializer.serialize_bytes(&self.to_compact())
+        }
+    }
+
+    pub fn serialize_compact6<S>(peers: &Peers, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_bytes(&peers.to_compact6())
+    }
+
+    /// Deserializes the compact `peers6` string.
+    pub fn deserialize_compact6<'de, D>(deserializer: D) -> Result<Peers, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let v = ByteBuf::deserialize(deserializer)?;
+        Peers::from_compact6(&v)
+            .ok_or_else(|| serde::de::Error::custom(format!("peers6 length is {}", v.len())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    #[test]
+    fn scrape_stats_default_missing_counts() {
+        let stats: ScrapeStats = serde_bencode::from_bytes(b"d8:completei3ee").unwrap();
+        assert_eq!(
+            (stats.complete, stats.incomplete, stats.downloaded),
+            (3, 0, 0)
+        );
+    }
+
+    fn peers(resp: &TrackerResponse) -> (&[SocketAddr], &[SocketAddr]) {
+        match &resp.resp_type {
+            ResponseType::Ok { peers, peers6, .. } => (&peers.0, &peers6.0),
+            ResponseType::Err { fail_reason } => panic!("{fail_reason}"),
+        }
+    }
+
+    #[test]
+    fn parses_compact_peers() {
+        let mut data = b"d8:intervali60e5:peers12:".to_vec();
+        data.extend([127, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0, 80]);
+        data.extend(b"6:peers618:");
+        data.extend(std::net::Ipv6Addr::LOCALHOST.octets());
+        data.extend([0x1a, 0xe1]);
+        data.push(b'e');
+
+        let resp: TrackerResponse = serde_bencode::from_bytes(&data).unwrap();
+        let (v4, v6) = peers(&resp);
+        assert_eq!(
+            v4,
+            [
+                "127.0.0.1:6881".parse().unwrap(),
+                "10.0.0.2:80".parse().unwrap()
+            ]
+        );
+        assert_eq!(v6, ["[::1]:6881".parse().unwrap()]);
+
+        // Serializing gives back the same compact strings.
+        assert_eq!(serde_bencode::to_bytes(&resp).unwrap(), data);
+    }
+
+    #[test]
+    fn parses_dict_peers_skipping_host_names() {
+        let data = b"d8:intervali60e5:peersl\
+            d2:ip9:127.0.0.17:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6881ee\
+            d2:ip3:::14:porti80ee\
+            d2:ip11:example.com4:porti80ee\
+            ee";
+        let resp: TrackerResponse = serde_bencode::from_bytes(data).unwrap();
+        let (v4, v6) = peers(&resp);
+        assert_eq!(
+            v4,
+            [
+                "127.0.0.1:6881".parse().unwrap(),
+                "[::1]:80".parse().unwrap()
+            ]
+        );
+        assert!(v6.is_empty());
+    }
+
+    #[test]
+    fn rejects_truncated_compact_peers() {
+        let data = b"d8:intervali60e5:peers5:abcdee";
+        assert!(serde_bencode::from_bytes::<TrackerResponse>(data).is_err());
+        assert!(Peers::from_compact6(&[0; 17]).is_none());
+    }
+
+    #[test]
+    fn failure_reason_is_an_error_response() {
+        for data in [
+            &b"d14:failure reason4:nopee"[..],
+            b"d14:failure reason4:nope8:intervali1800ee",
+        ] {
+            let resp: TrackerResponse = serde_bencode::from_bytes(data).unwrap();
+            assert!(matches!(
+                resp.resp_type,
+                ResponseType::Err { fail_reason } if fail_reason == "nope"
+            ));
+        }
+    }
+
+    #[test]
+    fn scrape_url_replaces_the_last_announce_segment() {
+        for (announce, scrape) in [
+            ("http://t.example/announce", Some("http://t.example/scrape")),
+            (
+                "http://t.example/x/announce.php?k=v",
+                Some("http://t.example/x/scrape.php?k=v"),
+            ),
+            ("http://t.example/announce/x", None),
+            ("http://t.example/a", None),
+            ("http://t.example/x%064announce", None),
+        ] {
+            assert_eq!(scrape_url(announce).as_deref(), scrape, "{announce}");
         }
     }
+
+    #[test]
+    fn percent_encode_keeps_unreserved_bytes() {
+        assert_eq!(percent_encode(b"aZ9-._~ /\x00\xff"), "aZ9-._~%20%2F%00%FF");
+    }
+
+    /// Serves an HTTP tracker that only tracks `allowed`, returning its announce URL.
+    async fn tracker(allowed: [u8; 20]) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let server = server::Server::new(
+            Duration::from_secs(60),
+            Some(std::collections::HashSet::from([allowed])),
+        );
+        tokio::spawn(std::sync::Arc::new(server).run(Some(listener), None));
+        url
+    }
+
+    /// The URL of a port nothing listens on.
+    async fn dead_tracker() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        format!("http://{}/announce", listener.local_addr().unwrap())
+    }
+
+    #[tokio::test]
+    async fn announce_falls_back_through_tiers_and_promotes() {
+        let info_hash = [1; 20];
+        let request = TrackerRequest::new(info_hash, "-RS0001-aaaaaaaaaaaa".to_string(), 1, 0);
+        let dead = dead_tracker().await;
+        let refusing = tracker([2; 20]).await;
+        let good = tracker(info_hash).await;
+        let backup = tracker(info_hash).await;
+
+        let mut trackers = TrackerList::from_tiers(vec![
+            vec![dead.clone(), refusing.clone()],
+            vec![backup.clone(), good.clone()],
+        ]);
+        let resp = trackers.announce(&request).await.unwrap();
+        assert!(matches!(resp.resp_type, ResponseType::Ok { .. }));
+        assert_eq!(trackers.interval(), Some(Duration::from_secs(60)));
+        // The tracker that answered moved to the front of its tier, the others kept their place.
+        let answered = trackers.tiers()[1][0].clone();
+        trackers.announce(&request).await.unwrap();
+        assert_eq!(trackers.tiers()[1][0], answered);
+        assert_eq!(trackers.tiers()[0].len(), 2);
+
+        let mut trackers = TrackerList::from_tiers(vec![vec![dead.clone(), refusing.clone()]]);
+        let err = trackers.announce(&request).await.unwrap_err().to_string();
+        assert!(err.contains(&dead) && err.contains(&refusing), "{err}");
+        assert!(err.contains("unregistered torrent"), "{err}");
+
+        let err = TrackerList::from_tiers(Vec::new())
+            .announce(&request)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "torrent has no trackers");
+    }
+
+    #[tokio::test]
+    async fn announce_promotes_the_tracker_that_answered() {
+        let info_hash = [1; 20];
+        let request = TrackerRequest::new(info_hash, "-RS0001-aaaaaaaaaaaa".to_string(), 1, 0);
+        let good = tracker(info_hash).await;
+        let tier = vec![dead_tracker().await, dead_tracker().await, good.clone()];
+        let mut trackers = TrackerList::from_tiers(vec![tier]);
+        trackers.announce(&request).await.unwrap();
+        assert_eq!(trackers.tiers()[0][0], good);
+    }
 }
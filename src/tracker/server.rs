@@ -0,0 +1,684 @@
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, HashMap, HashSet},
+    hash::BuildHasher,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use rand::seq::IteratorRandom;
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    time::timeout,
+};
+
+use super::{
+    peers::Peers,
+    udp::{ACTION_ANNOUNCE, ACTION_CONNECT, ACTION_ERROR, ACTION_SCRAPE, PROTOCOL_ID},
+    Event, ResponseType, ScrapeStats, TrackerResponse,
+};
+
+/// Peers handed out when the client does not say how many it wants.
+const DEFAULT_NUMWANT: usize = 50;
+/// Upper bound on the peers handed out in one response.
+const MAX_NUMWANT: usize = 200;
+/// How long an HTTP client gets to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest HTTP request head we read.
+const REQUEST_MAX: usize = 8192;
+/// UDP connection IDs change every minute and the previous one stays valid, so every ID is
+/// accepted for one to two minutes as BEP 15 asks of trackers.
+const CONNECTION_ID_PERIOD: u64 = 60;
+/// How long the stats of a swarm without peers are kept after its last announce.
+const SWARM_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// An announce from either protocol.
+#[derive(Debug, Clone)]
+pub struct Announce {
+    pub info_hash: [u8; 20],
+    /// The address peers reach the client on: its source IP and the port it announced.
+    pub addr: SocketAddr,
+    pub left: u64,
+    pub event: Option<Event>,
+    pub numwant: Option<usize>,
+}
+
+struct PeerEntry {
+    seeder: bool,
+    last_seen: Instant,
+}
+
+#[derive(Default)]
+struct SwarmPeers {
+    peers: HashMap<SocketAddr, PeerEntry>,
+    /// Completed events seen for the torrent.
+    downloaded: u64,
+    last_announce: Option<Instant>,
+}
+
+impl SwarmPeers {
+    fn stats(&self) -> ScrapeStats {
+        let complete = self.peers.values().filter(|p| p.seeder).count() as u64;
+        ScrapeStats {
+            complete,
+            incomplete: self.peers.len() as u64 - complete,
+            downloaded: self.downloaded,
+        }
+    }
+}
+
+/// A tracker that keeps its swarms in memory, serving HTTP announces and scrapes (BEP 3, BEP 48)
+/// and the UDP tracker protocol (BEP 15).
+///
+/// Peers that have not announced for two intervals are dropped. With a whitelist, announces and
+/// scrapes for any other torrent are refused.
+pub struct Server {
+    interval: Duration,
+    whitelist: Option<HashSet<[u8; 20]>>,
+    swarms: Mutex<HashMap<[u8; 20], SwarmPeers>>,
+    /// Keys the UDP connection IDs, so clients cannot make up their own.
+    secret: RandomState,
+}
+
+impl Server {
+    /// A tracker asking clients to announce every `interval`.
+    ///
+    /// Panics if `interval` is zero.
+    pub fn new(interval: Duration, whitelist: Option<HashSet<[u8; 20]>>) -> Self {
+        assert!(!interval.is_zero(), "tracker interval must not be zero");
+        Server {
+            interval,
+            whitelist,
+            swarms: Mutex::new(HashMap::new()),
+            secret: RandomState::new(),
+        }
+    }
+
+    /// Serves HTTP on `http` and UDP on `udp` until one of them fails.
+    pub async fn run(
+        self: Arc<Self>,
+        http: Option<TcpListener>,
+        udp: Option<UdpSocket>,
+    ) -> anyhow::Result<()> {
+        let http = async {
+            match http {
+                Some(listener) => Arc::clone(&self).serve_http(listener).await,
+                None => std::future::pending().await,
+            }
+        };
+        let udp = async {
+            match udp {
+                Some(socket) => Arc::clone(&self).serve_udp(socket).await,
+                None => std::future::pending().await,
+            }
+        };
+        let expire = async {
+            let mut interval = tokio::time::interval(self.interval);
+            loop {
+                interval.tick().await;
+                self.expire(Instant::now());
+            }
+        };
+        tokio::select! {
+            res = http => res,
+            res = udp => res,
+            _ = expire => unreachable!(),
+        }
+    }
+
+    /// Records an announce and picks peers for the client among those `wanted` accepts.
+    pub fn announce(
+        &self,
+        announce: &Announce,
+        wanted: impl Fn(&SocketAddr) -> bool,
+    ) -> anyhow::Result<(Vec<SocketAddr>, ScrapeStats)> {
+        self.check_allowed(&announce.info_hash)?;
+        let mut swarms = self.swarms.lock().unwrap();
+        let swarm = swarms.entry(announce.info_hash).or_default();
+        swarm.last_announce = Some(Instant::now());
+        match announce.event {
+            Some(Event::Stopped) => {
+                swarm.peers.remove(&announce.addr);
+                return Ok((Vec::new(), swarm.stats()));
+            }
+            Some(Event::Completed) => swarm.downloaded += 1,
+            _ => {}
+        }
+        swarm.peers.insert(
+            announce.addr,
+            PeerEntry {
+                seeder: announce.left == 0,
+                last_seen: Instant::now(),
+            },
+        );
+
+        let numwant = announce.numwant.unwrap_or(DEFAULT_NUMWANT).min(MAX_NUMWANT);
+        let peers = swarm
+            .peers
+            .keys()
+            .filter(|&&addr| addr != announce.addr && wanted(&addr))
+            .copied()
+            .choose_multiple(&mut rand::thread_rng(), numwant);
+        Ok((peers, swarm.stats()))
+    }
+
+    /// Stats of the torrents in `info_hashes`, or of every torrent when it is empty.
+    pub fn scrape(
+        &self,
+        info_hashes: &[[u8; 20]],
+    ) -> anyhow::Result<BTreeMap<[u8; 20], ScrapeStats>> {
+        for info_hash in info_hashes {
+            self.check_allowed(info_hash)?;
+        }
+        let swarms = self.swarms.lock().unwrap();
+        if info_hashes.is_empty() {
+            return Ok(swarms
+                .iter()
+                .map(|(&k, swarm)| (k, swarm.stats()))
+                .collect());
+        }
+        Ok(info_hashes
+            .iter()
+            .map(|info_hash| {
+                let stats = swarms.get(info_hash).map(SwarmPeers::stats);
+                (*info_hash, stats.unwrap_or_default())
+            })
+            .collect())
+    }
+
+    /// Drops peers that stopped announcing, and swarms that are left empty once nobody announced
+    /// for `SWARM_TTL`, whatever their completed count. Otherwise every made up info hash would
+    /// stay in memory for good.
+    fn expire(&self, now: Instant) {
+        let ttl = 2 * self.interval;
+        self.swarms.lock().unwrap().retain(|_, swarm| {
+            swarm
+                .peers
+                .retain(|_, peer| now.saturating_duration_since(peer.last_seen) < ttl);
+            !swarm.peers.is_empty()
+                || swarm
+                    .last_announce
+                    .is_some_and(|t| now.saturating_duration_since(t) < SWARM_TTL)
+        });
+    }
+
+    fn check_allowed(&self, info_hash: &[u8; 20]) -> anyhow::Result<()> {
+        match &self.whitelist {
+            Some(whitelist) if !whitelist.contains(info_hash) => bail!("unregistered torrent"),
+            _ => Ok(()),
+        }
+    }
+
+    async fn serve_http(self: Arc<Self>, listener: TcpListener) -> anyhow::Result<()> {
+        loop {
+            let (stream, addr) = listener.accept().await.context("accept connection")?;
+            let server = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = server.handle_http(stream, addr).await {
+                    eprintln!("http client {addr}: {e:#}");
+                }
+            });
+        }
+    }
+
+    async fn handle_http(&self, mut stream: TcpStream, addr: SocketAddr) -> anyhow::Result<()> {
+        let head = timeout(REQUEST_TIMEOUT, read_head(&mut stream))
+            .await
+            .context("request timed out")??;
+
+        // Only the request line matters, the headers are skipped.
+        let mut parts = head.lines().next().unwrap_or_default().split(' ');
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some(target)) => {
+                let (path, query) = target.split_once('?').unwrap_or((target, ""));
+                let params = parse_query(query);
+                match path {
+                    "/announce" => ("200 OK", self.http_announce(&params, addr)),
+                    "/scrape" => ("200 OK", self.http_scrape(&params)),
+                    _ => ("404 Not Found", b"not found".to_vec()),
+                }
+            }
+            _ => ("400 Bad Request", b"bad request".to_vec()),
+        };
+
+        let header = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(header.as_bytes()).await?;
+        stream.write_all(&body).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    fn http_announce(&self, params: &[(String, Vec<u8>)], addr: SocketAddr) -> Vec<u8> {
+        let ip = addr.ip().to_canonical();
+        let res = (|| {
+            let info_hash = param(params, "info_hash")
+                .and_then(|v| <[u8; 20]>::try_from(v).ok())
+                .context("missing or invalid info_hash")?;
+            let port = parse_param(params, "port")?.context("missing port")?;
+            let event = match param(params, "event") {
+                Some(b"started") => Some(Event::Started),
+                Some(b"completed") => Some(Event::Completed),
+                Some(b"stopped") => Some(Event::Stopped),
+                Some(b"") | None => None,
+                Some(_) => bail!("invalid event"),
+            };
+            let announce = Announce {
+                info_hash,
+                addr: SocketAddr::new(ip, port),
+                left: parse_param(params, "left")?.context("missing left")?,
+                event,
+                numwant: parse_param(params, "numwant")?,
+            };
+
+            let (peers, stats) = self.announce(&announce, |_| true)?;
+            let (peers, peers6) = peers.into_iter().partition(SocketAddr::is_ipv4);
+            Ok(ResponseType::Ok {
+                interval: self.interval.as_secs() as usize,
+                min_interval: None,
+                tracker_id: None,
+                warning: None,
+                complete: Some(stats.complete),
+                incomplete: Some(stats.incomplete),
+                external_ip: Some(ip),
+                peers: Peers(peers),
+                peers6: Peers(peers6),
+            })
+        })();
+
+        let resp_type = res.unwrap_or_else(|e: anyhow::Error| ResponseType::Err {
+            fail_reason: format!("{e:#}"),
+        });
+        serde_bencode::to_bytes(&TrackerResponse { resp_type }).expect("encode tracker response")
+    }
+
+    fn http_scrape(&self, params: &[(String, Vec<u8>)]) -> Vec<u8> {
+        #[derive(Serialize)]
+        struct ScrapeResponse {
+            files: BTreeMap<ByteBuf, ScrapeStats>,
+        }
+
+        let info_hashes: Option<Vec<[u8; 20]>> = params
+            .iter()
+            .filter(|(k, _)| k == "info_hash")
+            .map(|(_, v)| <[u8; 20]>::try_from(&v[..]).ok())
+            .collect();
+        let res = info_hashes
+            .context("invalid info_hash")
+            .and_then(|info_hashes| self.scrape(&info_hashes));
+        match res {
+            Ok(files) => serde_bencode::to_bytes(&ScrapeResponse {
+                files: files
+                    .into_iter()
+                    .map(|(k, v)| (ByteBuf::from(k.to_vec()), v))
+                    .collect(),
+            }),
+            Err(e) => serde_bencode::to_bytes(&TrackerResponse {
+                resp_type: ResponseType::Err {
+                    fail_reason: format!("{e:#}"),
+                },
+            }),
+        }
+        .expect("encode scrape response")
+    }
+
+    async fn serve_udp(self: Arc<Self>, socket: UdpSocket) -> anyhow::Result<()> {
+        let mut buf = vec![0; 2048];
+        loop {
+            let (len, addr) = match socket.recv_from(&mut buf).await {
+                Ok(recv) => recv,
+                // Errors from ICMP messages about earlier sends, nothing to do with this packet.
+                Err(e) => {
+                    eprintln!("udp receive: {e}");
+                    continue;
+                }
+            };
+            if let Some(resp) = self.handle_udp(&buf[..len], addr) {
+                if let Err(e) = socket.send_to(&resp, addr).await {
+                    eprintln!("udp client {addr}: {e}");
+                }
+            }
+        }
+    }
+
+    /// Answers one UDP packet, or ignores it if it does not look like a BEP 15 request.
+    fn handle_udp(&self, packet: &[u8], addr: SocketAddr) -> Option<Vec<u8>> {
+        if packet.len() < 16 {
+            return None;
+        }
+        let connection_id = u64::from_be_bytes(packet[0..8].try_into().unwrap());
+        let action = u32::from_be_bytes(packet[8..12].try_into().unwrap());
+        let transaction_id = &packet[12..16];
+        let body = &packet[16..];
+
+        let res = if action == ACTION_CONNECT {
+            if connection_id != PROTOCOL_ID {
+                return None;
+            }
+            Ok(self.connection_id(addr, 0).to_be_bytes().to_vec())
+        } else if connection_id != self.connection_id(addr, 0)
+            && connection_id != self.connection_id(addr, 1)
+        {
+            Err(anyhow::anyhow!("invalid connection id"))
+        } else {
+            match action {
+                ACTION_ANNOUNCE => self.udp_announce(body, addr),
+                ACTION_SCRAPE => self.udp_scrape(body),
+                _ => Err(anyhow::anyhow!("unknown action {action}")),
+            }
+        };
+
+        let (action, body) = match res {
+            Ok(body) => (action, body),
+            Err(e) => (ACTION_ERROR, format!("{e:#}").into_bytes()),
+        };
+        let mut resp = Vec::with_capacity(8 + body.len());
+        resp.extend(action.to_be_bytes());
+        resp.extend(transaction_id);
+        resp.extend(body);
+        Some(resp)
+    }
+
+    fn udp_announce(&self, body: &[u8], addr: SocketAddr) -> anyhow::Result<Vec<u8>> {
+        if body.len() < 82 {
+            bail!("announce request is too short");
+        }
+        let u64_at = |i: usize| u64::from_be_bytes(body[i..i + 8].try_into().unwrap());
+        let u32_at = |i: usize| u32::from_be_bytes(body[i..i + 4].try_into().unwrap());
+        let event = match u32_at(64) {
+            0 => None,
+            1 => Some(Event::Completed),
+            2 => Some(Event::Started),
+            3 => Some(Event::Stopped),
+            _ => bail!("invalid event"),
+        };
+        // A negative num_want asks for the default.
+        let numwant = i32::try_from(u32_at(76)).ok().map(|n| n as usize);
+        let port = u16::from_be_bytes(body[80..82].try_into().unwrap());
+        let ip = addr.ip().to_canonical();
+        let announce = Announce {
+            info_hash: body[0..20].try_into().unwrap(),
+            addr: SocketAddr::new(ip, port),
+            left: u64_at(48),
+            event,
+            numwant,
+        };
+
+        // The compact format of the response depends on the address family of the request.
+        let (peers, stats) = self.announce(&announce, |peer| peer.is_ipv4() == ip.is_ipv4())?;
+        let mut resp = Vec::new();
+        resp.extend((self.interval.as_secs() as u32).to_be_bytes());
+        resp.extend((stats.incomplete as u32).to_be_bytes());
+        resp.extend((stats.complete as u32).to_be_bytes());
+        if ip.is_ipv4() {
+            resp.extend(Peers(peers).to_compact());
+        } else {
+            resp.extend(Peers(peers).to_compact6());
+        }
+        Ok(resp)
+    }
+
+    fn udp_scrape(&self, body: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let info_hashes: Vec<[u8; 20]> = body
+            .chunks_exact(20)
+            .map(|hash| hash.try_into().unwrap())
+            .collect();
+        if info_hashes.is_empty() {
+            bail!("scrape request has no info hash");
+        }
+        let stats = self.scrape(&info_hashes)?;
+        let mut resp = Vec::with_capacity(12 * info_hashes.len());
+        for info_hash in &info_hashes {
+            let stats = stats[info_hash];
+            resp.extend((stats.complete as u32).to_be_bytes());
+            resp.extend((stats.downloaded as u32).to_be_bytes());
+            resp.extend((stats.incomplete as u32).to_be_bytes());
+        }
+        Ok(resp)
+    }
+
+    /// The connection ID for `addr`, `age` periods ago.
+    fn connection_id(&self, addr: SocketAddr, age: u64) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let period = now / CONNECTION_ID_PERIOD - age;
+        self.secret.hash_one((addr, period))
+    }
+}
+
+/// Reads the request line and headers of an HTTP request.
+async fn read_head(stream: &mut TcpStream) -> anyhow::Result<String> {
+    let mut buf = Vec::with_capacity(1024);
+    loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            buf.truncate(end);
+            return String::from_utf8(buf).context("request is not utf-8");
+        }
+        if buf.len() >= REQUEST_MAX {
+            bail!("request is too large");
+        }
+        if stream.read_buf(&mut buf).await? == 0 {
+            bail!("connection closed before the end of the request");
+        }
+    }
+}
+
+/// Splits a query string into its percent-decoded parameters, keeping repeated keys.
+fn parse_query(query: &str) -> Vec<(String, Vec<u8>)> {
+    query
+        .split('&')
+        .filter(|kv| !kv.is_empty())
+        .map(|kv| {
+            let (k, v) = kv.split_once('=').unwrap_or((kv, ""));
+            (
+                String::from_utf8_lossy(&percent_decode(k)).into_owned(),
+                percent_decode(v),
+            )
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> Vec<u8> {
+    let s = s.as_bytes();
+    let mut decoded = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        match s[i] {
+            b'%' if i + 2 < s.len() => {
+                match std::str::from_utf8(&s[i + 1..i + 3])
+                    .ok()
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 3;
+                        continue;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            b'+' => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    decoded
+}
+
+fn param<'a>(params: &'a [(String, Vec<u8>)], key: &str) -> Option<&'a [u8]> {
+    params
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.as_slice())
+}
+
+/// Parses a numeric parameter, `None` when it is missing.
+fn parse_param<T: std::str::FromStr>(
+    params: &[(String, Vec<u8>)],
+    key: &str,
+) -> anyhow::Result<Option<T>> {
+    let Some(v) = param(params, key) else {
+        return Ok(None);
+    };
+    std::str::from_utf8(v)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Some)
+        .with_context(|| format!("invalid {key}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announce(info_hash: [u8; 20], port: u16, event: Option<Event>) -> Announce {
+        Announce {
+            info_hash,
+            addr: SocketAddr::from(([127, 0, 0, 1], port)),
+            left: 0,
+            event,
+            numwant: None,
+        }
+    }
+
+    #[test]
+    fn empty_swarms_expire_even_with_completed_downloads() {
+        let server = Server::new(Duration::from_secs(60), None);
+        server
+            .announce(&announce([1; 20], 1, Some(Event::Completed)), |_| true)
+            .unwrap();
+        server
+            .announce(&announce([1; 20], 1, Some(Event::Stopped)), |_| true)
+            .unwrap();
+        server
+            .announce(&announce([2; 20], 2, None), |_| true)
+            .unwrap();
+
+        server.expire(Instant::now());
+        assert_eq!(server.scrape(&[]).unwrap().len(), 2);
+        assert_eq!(server.scrape(&[[1; 20]]).unwrap()[&[1; 20]].downloaded, 1);
+
+        server.expire(Instant::now() + SWARM_TTL);
+        assert!(server.scrape(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_query_decodes_and_keeps_repeated_keys() {
+        let params = parse_query("info_hash=%00%ffA&info_hash=b+c&&flag&bad=%4&odd=%zz1&k%65y=%41");
+        assert_eq!(
+            params,
+            [
+                ("info_hash".to_string(), vec![0, 0xff, b'A']),
+                ("info_hash".to_string(), b"b c".to_vec()),
+                ("flag".to_string(), Vec::new()),
+                ("bad".to_string(), b"%4".to_vec()),
+                ("odd".to_string(), b"%zz1".to_vec()),
+                ("key".to_string(), b"A".to_vec()),
+            ]
+        );
+        assert!(parse_query("").is_empty());
+    }
+
+    #[test]
+    fn parse_param_tells_missing_from_invalid() {
+        let params = parse_query("port=6881&left=-1");
+        assert_eq!(parse_param::<u16>(&params, "port").unwrap(), Some(6881));
+        assert_eq!(parse_param::<u64>(&params, "numwant").unwrap(), None);
+        assert!(parse_param::<u64>(&params, "left").is_err());
+    }
+
+    #[test]
+    fn http_announce_parses_client_requests() {
+        let server = Server::new(Duration::from_secs(60), None);
+        let peer: SocketAddr = "10.0.0.1:1".parse().unwrap();
+        let info_hash = *b"\x00\x01 %&+=?abcdefghijkl";
+        let mut request =
+            crate::tracker::TrackerRequest::new(info_hash, "-RS0001-aaaaaaaaaaaa".into(), 7, 0);
+        request.event = Some(Event::Completed);
+        let params = parse_query(&request.http_query_params());
+        let resp: TrackerResponse =
+            serde_bencode::from_bytes(&server.http_announce(&params, peer)).unwrap();
+        assert!(matches!(
+            resp.resp_type,
+            ResponseType::Ok {
+                complete: Some(1),
+                incomplete: Some(0),
+                ..
+            }
+        ));
+        let stats = server.scrape(&[info_hash]).unwrap()[&info_hash];
+        assert_eq!(stats.downloaded, 1);
+
+        for query in ["port=7&left=0", "info_hash=abc&port=7&left=0"] {
+            let resp: TrackerResponse =
+                serde_bencode::from_bytes(&server.http_announce(&parse_query(query), peer))
+                    .unwrap();
+            assert!(
+                matches!(resp.resp_type, ResponseType::Err { .. }),
+                "{query}"
+            );
+        }
+    }
+
+    #[test]
+    fn whitelist_refuses_other_torrents() {
+        let server = Server::new(Duration::from_secs(60), Some(HashSet::from([[1; 20]])));
+        assert!(server
+            .announce(&announce([1; 20], 1, None), |_| true)
+            .is_ok());
+        assert!(server
+            .announce(&announce([2; 20], 1, None), |_| true)
+            .is_err());
+        assert!(server.scrape(&[[2; 20]]).is_err());
+    }
+
+    #[test]
+    fn udp_requests_need_a_connection_id() {
+        let server = Server::new(Duration::from_secs(60), None);
+        let addr: SocketAddr = "10.0.0.1:1".parse().unwrap();
+        let header = |connection_id: u64, action: u32| {
+            let mut packet = connection_id.to_be_bytes().to_vec();
+            packet.extend(action.to_be_bytes());
+            packet.extend(9u32.to_be_bytes());
+            packet
+        };
+
+        assert!(server.handle_udp(&[0; 15], addr).is_none());
+        assert!(server
+            .handle_udp(&header(1, ACTION_CONNECT), addr)
+            .is_none());
+        let resp = server
+            .handle_udp(&header(PROTOCOL_ID, ACTION_CONNECT), addr)
+            .unwrap();
+        assert_eq!(
+            resp[..8],
+            [ACTION_CONNECT, 9].map(u32::to_be_bytes).concat()
+        );
+        let connection_id = u64::from_be_bytes(resp[8..16].try_into().unwrap());
+
+        let mut scrape = header(connection_id, ACTION_SCRAPE);
+        scrape.extend([1; 20]);
+        let resp = server.handle_udp(&scrape, addr).unwrap();
+        assert_eq!(resp[..4], ACTION_SCRAPE.to_be_bytes());
+        assert_eq!(resp[8..], [0; 12]);
+
+        // Another address cannot use the connection ID.
+        let other: SocketAddr = "10.0.0.2:1".parse().unwrap();
+        let resp = server.handle_udp(&scrape, other).unwrap();
+        assert_eq!(resp[..4], ACTION_ERROR.to_be_bytes());
+        assert_eq!(&resp[8..], b"invalid connection id");
+
+        let mut short = header(connection_id, ACTION_ANNOUNCE);
+        short.extend([0; 81]);
+        let resp = server.handle_udp(&short, addr).unwrap();
+        assert_eq!(resp[..4], ACTION_ERROR.to_be_bytes());
+    }
+}
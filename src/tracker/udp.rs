@@ -0,0 +1,334 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use tokio::{net::UdpSocket, time::timeout};
+
+use super::{peers::Peers, Event, ResponseType, ScrapeStats, TrackerRequest, TrackerResponse};
+
+/// Magic constant that starts every connect request.
+pub(super) const PROTOCOL_ID: u64 = 0x41727101980;
+/// Clients can use a connection ID for one minute after receiving it.
+const CONNECTION_TTL: Duration = Duration::from_secs(60);
+
+pub(super) const ACTION_CONNECT: u32 = 0;
+pub(super) const ACTION_ANNOUNCE: u32 = 1;
+pub(super) const ACTION_SCRAPE: u32 = 2;
+pub(super) const ACTION_ERROR: u32 = 3;
+/// Info hashes that fit in one scrape packet.
+const SCRAPE_MAX: usize = 74;
+
+/// A tracker speaking the UDP tracker protocol (BEP 15).
+///
+/// The connection ID is cached for as long as the tracker lets us use it, and requests are
+/// retransmitted after `15 * 2^n` seconds as the spec describes.
+#[derive(Debug)]
+pub struct UdpTracker {
+    host: String,
+    socket: Option<(UdpSocket, SocketAddr)>,
+    connection: Option<(u64, Instant)>,
+    /// Retransmissions before giving up. The spec goes up to 8, which takes over an hour
+    /// before a dead tracker is given up on.
+    pub max_retries: u32,
+}
+
+impl UdpTracker {
+    /// Parses a `udp://host:port[/announce]` URL.
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        let url = reqwest::Url::parse(url).context("parse tracker url")?;
+        if url.scheme() != "udp" {
+            bail!("{url} is not a UDP tracker");
+        }
+        let host = url.host_str().context("tracker url has no host")?;
+        let port = url.port().context("tracker url has no port")?;
+        Ok(UdpTracker {
+            host: format!("{host}:{port}"),
+            socket: None,
+            connection: None,
+            max_retries: 2,
+        })
+    }
+
+    pub async fn announce(&mut self, request: &TrackerRequest) -> anyhow::Result<TrackerResponse> {
+        let peer_id: [u8; 20] = request
+            .peer_id
+            .as_bytes()
+            .try_into()
+            .context("peer id should be 20 bytes")?;
+        let event: u32 = match request.event {
+            None => 0,
+            Some(Event::Completed) => 1,
+            Some(Event::Started) => 2,
+            Some(Event::Stopped) => 3,
+        };
+
+        let mut payload = Vec::with_capacity(82);
+        payload.extend(request.info_hash);
+        payload.extend(peer_id);
+        payload.extend(request.downloaded.to_be_bytes());
+        payload.extend(request.left.to_be_bytes());
+        payload.extend(request.uploaded.to_be_bytes());
+        payload.extend(event.to_be_bytes());
+        payload.extend(0u32.to_be_bytes()); // ip: let the tracker use the source address
+        payload.extend(request.key.unwrap_or_default().to_be_bytes());
+        // -1 lets the tracker pick.
+        let num_want = request
+            .numwant
+            .map_or(-1, |n| n.min(i32::MAX as u32) as i32);
+        payload.extend(num_want.to_be_bytes());
+        payload.extend(request.port.to_be_bytes());
+
+        let resp = self.request(ACTION_ANNOUNCE, &payload).await?;
+        if resp.len() < 12 {
+            bail!("announce response is too short");
+        }
+        let field = |i: usize| u32::from_be_bytes(resp[4 * i..4 * i + 4].try_into().unwrap());
+        let (interval, leechers, seeders) = (field(0), field(1), field(2));
+        // Trackers answer with peers of the address family we reached them over.
+        let ipv6 = matches!(&self.socket, Some((_, addr)) if addr.is_ipv6());
+        let peers = if ipv6 {
+            Peers::from_compact6(&resp[12..])
+        } else {
+            Peers::from_compact(&resp[12..])
+        };
+        let peers = peers.context("malformed peer list")?;
+        Ok(TrackerResponse {
+            resp_type: ResponseType::Ok {
+                interval: interval as usize,
+                min_interval: None,
+                tracker_id: None,
+                warning: None,
+                complete: Some(seeders as u64),
+                incomplete: Some(leechers as u64),
+                external_ip: None,
+                peers,
+                peers6: Peers::default(),
+            },
+        })
+    }
+
+    /// Scrapes the torrents in `info_hashes`, returning their stats in the same order.
+    pub async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> anyhow::Result<Vec<ScrapeStats>> {
+        let mut stats = Vec::with_capacity(info_hashes.len());
+        for chunk in info_hashes.chunks(SCRAPE_MAX) {
+            let resp = self.request(ACTION_SCRAPE, &chunk.concat()).await?;
+            if resp.len() < 12 * chunk.len() {
+                bail!("scrape response is too short");
+            }
+            let field = |i: usize| u32::from_be_bytes(resp[4 * i..4 * i + 4].try_into().unwrap());
+            stats.extend((0..chunk.len()).map(|i| ScrapeStats {
+                complete: field(3 * i) as u64,
+                downloaded: field(3 * i + 1) as u64,
+                incomplete: field(3 * i + 2) as u64,
+            }));
+        }
+        Ok(stats)
+    }
+
+    /// Sends `payload` for `action` and returns the response body after the action and
+    /// transaction ID, reconnecting when the connection ID expired.
+    async fn request(&mut self, action: u32, payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+        for n in 0..=self.max_retries {
+            let connection_id = self.connection_id().await?;
+            let mut packet = Vec::with_capacity(16 + payload.len());
+            packet.extend(connection_id.to_be_bytes());
+            packet.extend(action.to_be_bytes());
+            let transaction_id = rand::random::<u32>();
+            packet.extend(transaction_id.to_be_bytes());
+            packet.extend(payload);
+
+            if let Some(resp) = self.attempt(&packet, transaction_id, n).await? {
+                return check_action(resp, action);
+            }
+        }
+        bail!("tracker did not answer")
+    }
+
+    async fn connection_id(&mut self) -> anyhow::Result<u64> {
+        if let Some((id, at)) = self.connection {
+            if at.elapsed() < CONNECTION_TTL {
+                return Ok(id);
+            }
+        }
+
+        for n in 0..=self.max_retries {
+            let mut packet = Vec::with_capacity(16);
+            packet.extend(PROTOCOL_ID.to_be_bytes());
+            packet.extend(ACTION_CONNECT.to_be_bytes());
+            let transaction_id = rand::random::<u32>();
+            packet.extend(transaction_id.to_be_bytes());
+
+            if let Some(resp) = self.attempt(&packet, transaction_id, n).await? {
+                let resp = check_action(resp, ACTION_CONNECT)?;
+                let id = u64::from_be_bytes(
+                    resp.get(..8)
+                        .context("connect response is too short")?
+                        .try_into()
+                        .unwrap(),
+                );
+                self.connection = Some((id, Instant::now()));
+                return Ok(id);
+            }
+        }
+        bail!("tracker did not answer the connect request")
+    }
+
+    /// Sends a packet once and waits `15 * 2^n` seconds for the response with our transaction
+    /// ID. Returns `None` on timeout.
+    async fn attempt(
+        &mut self,
+        packet: &[u8],
+        transaction_id: u32,
+        n: u32,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        if self.socket.is_none() {
+            let addr = tokio::net::lookup_host(&self.host)
+                .await
+                .with_context(|| format!("resolve {}", self.host))?
+                .next()
+                .with_context(|| format!("{} has no address", self.host))?;
+            let bind = if addr.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            };
+            self.socket = Some((UdpSocket::bind(bind).await?, addr));
+        }
+        let (socket, addr) = self.socket.as_ref().unwrap();
+        socket
+            .send_to(packet, addr)
+            .await
+            .context("send to tracker")?;
+
+        let wait = Duration::from_secs(15 << n);
+        let deadline = Instant::now() + wait;
+        let mut buf = vec![0; 65536];
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let Ok(recv) = timeout(left, socket.recv_from(&mut buf)).await else {
+                return Ok(None);
+            };
+            let (len, from) = recv.context("receive from tracker")?;
+            // Stray packets (late answers to earlier attempts, or other senders) are skipped.
+            if from != *addr || len < 8 || buf[4..8] != transaction_id.to_be_bytes() {
+                continue;
+            }
+            return Ok(Some(buf[..len].to_vec()));
+        }
+    }
+}
+
+/// Strips the action and transaction ID, turning error responses into errors.
+fn check_action(resp: Vec<u8>, action: u32) -> anyhow::Result<Vec<u8>> {
+    let got = u32::from_be_bytes(resp[0..4].try_into().unwrap());
+    if got == ACTION_ERROR {
+        bail!("{}", String::from_utf8_lossy(&resp[8..]));
+    }
+    if got != action {
+        bail!("tracker answered with action {got}, expected {action}");
+    }
+    Ok(resp[8..].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Receives one packet on `socket`, checks its header and answers with `body` after the
+    /// action and transaction ID. Returns the payload after the header.
+    async fn answer(socket: &UdpSocket, connection_id: u64, action: u32, body: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0; 2048];
+        let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(buf[..8], connection_id.to_be_bytes());
+        assert_eq!(buf[8..12], action.to_be_bytes());
+        let mut resp = action.to_be_bytes().to_vec();
+        resp.extend(&buf[12..16]);
+        resp.extend(body);
+        socket.send_to(&resp, from).await.unwrap();
+        buf[16..len].to_vec()
+    }
+
+    #[tokio::test]
+    async fn announce_and_scrape_packets() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        let tracker = tokio::spawn(async move {
+            let mut tracker = UdpTracker::new(&url).unwrap();
+            let mut request =
+                TrackerRequest::new([1; 20], "-RS0001-aaaaaaaaaaaa".to_string(), 6881, 100);
+            request.uploaded = 2;
+            request.downloaded = 3;
+            request.event = Some(Event::Started);
+            request.key = Some(7);
+            let resp = tracker.announce(&request).await.unwrap();
+            let stats = tracker.scrape(&[[1; 20], [2; 20]]).await.unwrap();
+            (resp, stats)
+        });
+
+        let connect = answer(&socket, PROTOCOL_ID, ACTION_CONNECT, &42u64.to_be_bytes()).await;
+        assert!(connect.is_empty());
+
+        let mut body = [900u32, 5, 6].map(u32::to_be_bytes).concat();
+        body.extend([127, 0, 0, 1, 0x1a, 0xe1]);
+        let announce = answer(&socket, 42, ACTION_ANNOUNCE, &body).await;
+        let mut expected = [1; 20].to_vec();
+        expected.extend(b"-RS0001-aaaaaaaaaaaa");
+        expected.extend(3u64.to_be_bytes());
+        expected.extend(100u64.to_be_bytes());
+        expected.extend(2u64.to_be_bytes());
+        expected.extend(2u32.to_be_bytes());
+        expected.extend(0u32.to_be_bytes());
+        expected.extend(7u32.to_be_bytes());
+        expected.extend((-1i32).to_be_bytes());
+        expected.extend(6881u16.to_be_bytes());
+        assert_eq!(announce, expected);
+
+        let body = [1u32, 2, 3, 4, 5, 6].map(u32::to_be_bytes).concat();
+        let scrape = answer(&socket, 42, ACTION_SCRAPE, &body).await;
+        assert_eq!(scrape, [[1; 20], [2; 20]].concat());
+
+        let (resp, stats) = tracker.await.unwrap();
+        let ResponseType::Ok {
+            interval,
+            complete,
+            incomplete,
+            peers,
+            ..
+        } = resp.resp_type
+        else {
+            panic!("announce failed");
+        };
+        assert_eq!((interval, complete, incomplete), (900, Some(6), Some(5)));
+        assert_eq!(peers.0, ["127.0.0.1:6881".parse().unwrap()]);
+        assert_eq!(
+            stats
+                .iter()
+                .map(|s| (s.complete, s.downloaded, s.incomplete))
+                .collect::<Vec<_>>(),
+            [(1, 2, 3), (4, 5, 6)]
+        );
+    }
+
+    #[test]
+    fn check_action_turns_errors_into_errors() {
+        let mut resp = ACTION_ERROR.to_be_bytes().to_vec();
+        resp.extend([0; 4]);
+        resp.extend(b"unregistered torrent");
+        let err = check_action(resp, ACTION_ANNOUNCE).unwrap_err();
+        assert_eq!(err.to_string(), "unregistered torrent");
+
+        let mut resp = ACTION_SCRAPE.to_be_bytes().to_vec();
+        resp.extend([0; 4]);
+        assert!(check_action(resp.clone(), ACTION_ANNOUNCE).is_err());
+        assert!(check_action(resp, ACTION_SCRAPE).unwrap().is_empty());
+    }
+
+    #[test]
+    fn new_needs_a_udp_url_with_a_port() {
+        assert!(UdpTracker::new("udp://t.example:6969/announce").is_ok());
+        assert!(UdpTracker::new("udp://t.example/announce").is_err());
+        assert!(UdpTracker::new("http://t.example:6969/announce").is_err());
+    }
+}
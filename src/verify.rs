@@ -0,0 +1,193 @@
+use std::fs::File;
+
+use sha1::{Digest, Sha1};
+
+use crate::{
+    storage::{par_pieces, read_range, split},
+    torrent::{FileSpan, Info},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Complete,
+    /// Some of the data is not there: a file is absent or shorter than it should be.
+    Missing,
+    /// All of the data is there but does not match the hashes.
+    Corrupt,
+}
+
+/// Result of checking local data against the piece hashes of a torrent.
+///
+/// A file gets the worst status of the pieces it overlaps, so a file next to a missing one can
+/// be reported missing too when they share a piece.
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub pieces: Vec<Status>,
+    pub files: Vec<(FileSpan, Status)>,
+}
+
+impl Report {
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(|&s| s == Status::Complete)
+    }
+
+    pub fn count(&self, status: Status) -> usize {
+        self.pieces.iter().filter(|&&s| s == status).count()
+    }
+}
+
+/// Hashes every piece of the files in `layout` with one thread per core. Nothing is created or
+/// modified on disk.
+pub fn verify(info: &Info, layout: &[FileSpan]) -> Report {
+    let npieces = info.pieces.0.len();
+    let present: Vec<bool> = layout
+        .iter()
+        .map(|span| {
+            std::fs::metadata(&span.path).is_ok_and(|m| m.is_file() && m.len() >= span.length)
+        })
+        .collect();
+
+    let pieces = par_pieces(npieces, layout.len(), |files, piece_i| {
+        check_piece(info, layout, &present, files, piece_i)
+    });
+
+    let files = layout
+        .iter()
+        .zip(&present)
+        .map(|(span, &present)| {
+            let status = if !present {
+                Status::Missing
+            } else if span.length == 0 {
+                Status::Complete
+            } else {
+                let first = (span.offset / info.plength) as usize;
+                let last = ((span.offset + span.length - 1) / info.plength) as usize;
+                let pieces = &pieces[first..=last];
+                if pieces.contains(&Status::Corrupt) {
+                    Status::Corrupt
+                } else if pieces.contains(&Status::Missing) {
+                    Status::Missing
+                } else {
+                    Status::Complete
+                }
+            };
+            (span.clone(), status)
+        })
+        .collect();
+
+    Report { pieces, files }
+}
+
+fn check_piece(
+    info: &Info,
+    layout: &[FileSpan],
+    present: &[bool],
+    files: &mut [Option<File>],
+    piece_i: usize,
+) -> Status {
+    let offset = piece_i as u64 * info.plength;
+    let mut data = vec![0; info.piece_size(piece_i) as usize];
+    if split(layout.iter(), offset, data.len()).any(|(i, _, _)| !present[i])
+        || read_range(layout, files, offset, &mut data).is_err()
+    {
+        return Status::Missing;
+    }
+
+    let mut hasher = Sha1::new();
+    hasher.update(&data);
+    let hash: [u8; 20] = hasher.finalize().into();
+    if hash == info.pieces.0[piece_i] {
+        Status::Complete
+    } else {
+        Status::Corrupt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::{Hashes, Keys};
+    use Status::*;
+
+    /// Writes files of 20000, 30000, 0 and 10000 bytes in pieces of 16 KiB, so pieces 1 and 3
+    /// span two files each.
+    fn setup(dir: &std::path::Path) -> (Info, Vec<FileSpan>, Vec<u8>) {
+        let data: Vec<u8> = (0..60000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let files = [("a", 20000), ("b", 30000), ("c", 0), ("d", 10000)];
+        let info = Info {
+            name: "t".to_string(),
+            plength: 16384,
+            pieces: Hashes(data.chunks(16384).map(|c| Sha1::digest(c).into()).collect()),
+            private: None,
+            keys: Keys::MultiFile {
+                files: files
+                    .iter()
+                    .map(|&(name, length)| crate::torrent::File {
+                        length,
+                        path: vec![name.to_string()],
+                    })
+                    .collect(),
+            },
+        };
+        let layout = info.layout(dir).unwrap();
+        for span in &layout {
+            let range = span.offset as usize..(span.offset + span.length) as usize;
+            std::fs::write(&span.path, &data[range]).unwrap();
+        }
+        (info, layout, data)
+    }
+
+    fn file_statuses(report: &Report) -> Vec<Status> {
+        report.files.iter().map(|(_, status)| *status).collect()
+    }
+
+    #[test]
+    fn complete_data_checks_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let (info, layout, _) = setup(dir.path());
+        let report = verify(&info, &layout);
+        assert!(report.is_complete());
+        assert_eq!(report.count(Complete), 4);
+        assert_eq!(file_statuses(&report), [Complete; 4]);
+    }
+
+    #[test]
+    fn corruption_taints_every_file_of_the_piece() {
+        let dir = tempfile::tempdir().unwrap();
+        let (info, layout, data) = setup(dir.path());
+        let mut d = data[50000..].to_vec();
+        d[5000] ^= 1;
+        std::fs::write(&layout[3].path, d).unwrap();
+
+        let report = verify(&info, &layout);
+        assert!(!report.is_complete());
+        assert_eq!(report.pieces, [Complete, Complete, Complete, Corrupt]);
+        assert_eq!(
+            file_statuses(&report),
+            [Complete, Corrupt, Complete, Corrupt]
+        );
+    }
+
+    #[test]
+    fn absent_and_short_files_are_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let (info, layout, data) = setup(dir.path());
+        std::fs::remove_file(&layout[0].path).unwrap();
+        let report = verify(&info, &layout);
+        assert_eq!(report.pieces, [Missing, Missing, Complete, Complete]);
+        assert_eq!(report.count(Missing), 2);
+        assert_eq!(
+            file_statuses(&report),
+            [Missing, Missing, Complete, Complete]
+        );
+
+        std::fs::write(&layout[0].path, &data[..20000]).unwrap();
+        std::fs::write(&layout[3].path, &data[50000..59999]).unwrap();
+        let report = verify(&info, &layout);
+        assert_eq!(report.pieces, [Complete, Complete, Complete, Missing]);
+        assert_eq!(
+            file_statuses(&report),
+            [Complete, Missing, Complete, Missing]
+        );
+    }
+}